@@ -0,0 +1,226 @@
+extern crate livesplit_server;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use livesplit_server::{LSState, Recipient, rest_api};
+use livesplit_server::config::Config;
+use livesplit_server::commands::{self, Invoker, Reply};
+
+static USAGE: &'static str = "Usage: livesplit-cli [--user <id>] [--name <name>] [--listen \
+                              <address>] [--rest]
+
+Reads bot commands like `!split` or `!create-race` from stdin, or from every
+connection to the address when listening on a TCP socket. Use `/user <id>
+[name]` to switch to a different user.";
+
+struct Options {
+    user: u64,
+    name: String,
+    listen: Option<String>,
+    rest: bool,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        user: 0,
+        name: String::from("Local User"),
+        listen: None,
+        rest: false,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--user" => {
+                options.user = args.next()
+                    .and_then(|id| id.parse().ok())
+                    .ok_or_else(|| String::from("Expected a numeric user id"))?;
+            }
+            "--name" => {
+                options.name = args.next()
+                    .ok_or_else(|| String::from("Expected a user name"))?;
+            }
+            "--listen" => {
+                options.listen = Some(args.next()
+                                          .ok_or_else(|| String::from("Expected an address"))?);
+            }
+            "--rest" => options.rest = true,
+            _ => return Err(format!("Unknown argument `{}`", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn count_down<W: Write>(output: &mut W, seconds: u64) -> io::Result<()> {
+    for remaining_seconds in (1..seconds + 1).rev() {
+        writeln!(output, "{}", remaining_seconds)?;
+        output.flush()?;
+        thread::sleep(Duration::from_secs(1));
+    }
+    writeln!(output, "Go!")
+}
+
+fn print_replies<W: Write>(state: &LSState,
+                           invoker: &Invoker,
+                           output: &mut W,
+                           replies: Vec<Reply>)
+                           -> io::Result<()> {
+    for reply in commands::countdowns_last(replies) {
+        match reply {
+            Reply::Text(text) => writeln!(output, "{}", text)?,
+            Reply::Embed(embed) |
+            Reply::RaceStatus { embed, .. } => writeln!(output, "{}", embed.to_text())?,
+            Reply::File {
+                name,
+                content,
+                text,
+            } => {
+                File::create(&name)?.write_all(&content)?;
+                writeln!(output, "{}\n(Saved {})", text, name)?;
+            }
+            Reply::Direct(user, text) => writeln!(output, "(To {}) {}", user, text)?,
+            Reply::Countdown(seconds) => {
+                writeln!(output, "All entrants are ready!")?;
+                count_down(output, seconds)?;
+                match commands::start_race(invoker, state) {
+                    Ok(replies) => print_replies(state, invoker, output, replies)?,
+                    Err(e) => writeln!(output, "{}", e)?,
+                }
+            }
+            Reply::AsyncCountdown { race, seconds } => {
+                writeln!(output, "Get ready for async race #{}!", race)?;
+                count_down(output, seconds)?;
+                match commands::start_async_run(invoker, race, state) {
+                    Ok(replies) => print_replies(state, invoker, output, replies)?,
+                    Err(e) => writeln!(output, "{}", e)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn switch_user<W: Write>(invoker: &mut Invoker, params: &[&str], output: &mut W) -> io::Result<()> {
+    match params.get(0).and_then(|id| id.parse().ok()) {
+        Some(id) => {
+            invoker.id = id;
+            invoker.name = if params.len() > 1 {
+                params[1..].join(" ")
+            } else {
+                format!("User {}", id)
+            };
+            writeln!(output, "You are now {} ({})", invoker.name, invoker.id)
+        }
+        None => writeln!(output, "Usage: /user <id> [name]"),
+    }
+}
+
+fn session<R: BufRead, W: Write>(state: &LSState,
+                                 input: R,
+                                 output: &mut W,
+                                 mut invoker: Invoker)
+                                 -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.starts_with("/user") {
+            let params = line.split_whitespace().skip(1).collect::<Vec<_>>();
+            switch_user(&mut invoker, &params, output)?;
+        } else if let Some((command, params)) =
+            commands::parse(&state.prefix(invoker.guild), line)
+                .or_else(|| commands::parse("", line)) {
+            match commands::execute(state, &invoker, &command, params) {
+                Some(Ok(replies)) => print_replies(state, &invoker, output, replies)?,
+                Some(Err(e)) => writeln!(output, "{}", e)?,
+                None => writeln!(output, "Unknown command `{}`", command)?,
+            }
+        }
+
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}\n\n{}", e, USAGE);
+            return;
+        }
+    };
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "{}", e);
+            return;
+        }
+    };
+    let state = Arc::new(LSState::new(config));
+
+    if options.rest {
+        rest_api::start(state.clone());
+    }
+
+    state.add_announcer(Box::new(|_, recipient, text| {
+        match recipient {
+            Recipient::Channel(_) => println!("{}", text),
+            Recipient::User(user) => println!("(To {}) {}", user, text),
+        }
+        true
+    }));
+    commands::watch_races(state.clone());
+
+    let invoker = || {
+        Invoker {
+            id: options.user,
+            name: options.name.clone(),
+            channel: 0,
+            guild: 0,
+            // Whoever runs the bot locally is in charge of it.
+            manage_guild: true,
+            roles: Vec::new(),
+        }
+    };
+
+    if let Some(ref address) = options.listen {
+        let listener = match TcpListener::bind(address.as_str()) {
+            Ok(listener) => listener,
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "Couldn't listen on {}: {}", address, e);
+                return;
+            }
+        };
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let state = state.clone();
+            let invoker = invoker();
+            thread::spawn(move || {
+                let input = match stream.try_clone() {
+                    Ok(input) => BufReader::new(input),
+                    Err(_) => return,
+                };
+                let mut output = stream;
+                let _ = session(&state, input, &mut output, invoker);
+            });
+        }
+    } else {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        if let Err(e) = session(&state, stdin.lock(), &mut stdout.lock(), invoker()) {
+            let _ = writeln!(io::stderr(), "{}", e);
+        }
+    }
+}
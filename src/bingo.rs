@@ -0,0 +1,648 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use speedrun_bingo::{self, Board, Mode, Template};
+use image::png::PNGEncoder;
+use image::{ColorType, RgbaImage};
+use error::Error;
+
+static CALAMITY: &[u8] = include_bytes!("../Calamity-Bold.ttf");
+static FIRA_SANS: &[u8] = include_bytes!("../FiraSans-Regular.ttf");
+const CELL_SIZE: u32 = 150;
+const FONT_SIZE: f32 = 20.0;
+const CELL_PADDING: i32 = 5;
+
+const LINES: [[usize; 5]; 12] = [[0, 1, 2, 3, 4],
+                                 [5, 6, 7, 8, 9],
+                                 [10, 11, 12, 13, 14],
+                                 [15, 16, 17, 18, 19],
+                                 [20, 21, 22, 23, 24],
+                                 [0, 5, 10, 15, 20],
+                                 [1, 6, 11, 16, 21],
+                                 [2, 7, 12, 17, 22],
+                                 [3, 8, 13, 18, 23],
+                                 [4, 9, 14, 19, 24],
+                                 [0, 6, 12, 18, 24],
+                                 [4, 8, 12, 16, 20]];
+
+const PLAYER_COLORS: [(&'static str, [u8; 3]); 8] = [("Red", [0xe0, 0x3c, 0x3c]),
+                                                     ("Blue", [0x3c, 0x78, 0xe0]),
+                                                     ("Green", [0x3c, 0xc8, 0x50]),
+                                                     ("Orange", [0xf0, 0x96, 0x1e]),
+                                                     ("Purple", [0x96, 0x50, 0xdc]),
+                                                     ("Teal", [0x1e, 0xb4, 0xb4]),
+                                                     ("Pink", [0xf0, 0x6e, 0xc8]),
+                                                     ("Yellow", [0xe6, 0xd2, 0x28])];
+const HIGHLIGHT_OPACITY: f32 = 0.45;
+
+#[derive(Clone)]
+pub struct Variant {
+    pub template: &'static str,
+    /// The JSON of the template.
+    source: &'static str,
+    pub mode: &'static str,
+    /// In lockout bingo each goal can only be claimed by a single entrant.
+    pub lockout: bool,
+}
+
+pub struct BingoGame {
+    pub variant: Variant,
+    pub seed: u32,
+    board: Board,
+    players: Vec<u64>,
+    marks: HashMap<u64, [bool; 25]>,
+}
+
+pub enum Mark {
+    Marked,
+    AlreadyMarked,
+    ClaimedBy(u64),
+}
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Font {
+    Calamity,
+    FiraSans,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderOptions {
+    pub cell_size: u32,
+    pub cell_padding: i32,
+    pub font: Font,
+    pub font_size: f32,
+    pub background: Option<[u8; 3]>,
+    pub border: Option<[u8; 3]>,
+    pub high_contrast: bool,
+}
+
+#[derive(Serialize)]
+pub struct BoardJson {
+    template: String,
+    seed: u32,
+    mode: String,
+    goals: Vec<Vec<String>>,
+}
+
+impl Font {
+    fn bytes(&self) -> &'static [u8] {
+        match *self {
+            Font::Calamity => CALAMITY,
+            Font::FiraSans => FIRA_SANS,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Font::Calamity => "calamity",
+            Font::FiraSans => "fira",
+        }
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            cell_size: CELL_SIZE,
+            cell_padding: CELL_PADDING,
+            font: Font::Calamity,
+            font_size: FONT_SIZE,
+            background: None,
+            border: None,
+            high_contrast: false,
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Applies options of the form `size=150`, `font=fira`, `text=20`,
+    /// `padding=5`, `background=#1e1e1e`, `border=#ffffff` and `contrast`.
+    /// Returns `false` if the parameter isn't a render option.
+    pub fn apply(&mut self, param: &str) -> bool {
+        let param = param.to_lowercase();
+        let mut splits = param.splitn(2, '=');
+        let (key, value) = (splits.next().unwrap_or(""), splits.next().unwrap_or(""));
+
+        match key {
+            "contrast" | "high-contrast" => {
+                self.high_contrast = value != "off" && value != "false";
+            }
+            "size" => {
+                match value.parse() {
+                    Ok(size) if size >= 50 && size <= 400 => self.cell_size = size,
+                    _ => return false,
+                }
+            }
+            "padding" => {
+                match value.parse() {
+                    Ok(padding) if padding >= 0 && padding <= 50 => self.cell_padding = padding,
+                    _ => return false,
+                }
+            }
+            "text" | "font-size" => {
+                match value.parse() {
+                    Ok(size) if size >= 6.0 && size <= 60.0 => self.font_size = size,
+                    _ => return false,
+                }
+            }
+            "font" => {
+                self.font = match value {
+                    "calamity" => Font::Calamity,
+                    "fira" | "firasans" | "fira-sans" => Font::FiraSans,
+                    _ => return false,
+                };
+            }
+            "background" | "bg" => {
+                match parse_color(value) {
+                    Some(color) => self.background = Some(color),
+                    None => return false,
+                }
+            }
+            "border" => {
+                match parse_color(value) {
+                    Some(color) => self.border = Some(color),
+                    None => return false,
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Applies all the render options in the parameters, ignoring everything
+    /// else.
+    pub fn apply_params<S: AsRef<str>>(&mut self, params: &[S]) {
+        for param in params {
+            self.apply(param.as_ref());
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = format!("size={} padding={} font={} text={}",
+                               self.cell_size,
+                               self.cell_padding,
+                               self.font.name(),
+                               self.font_size);
+        if let Some(color) = self.background {
+            text.push_str(&format!(" background={}", format_color(color)));
+        }
+        if let Some(color) = self.border {
+            text.push_str(&format!(" border={}", format_color(color)));
+        }
+        if self.high_contrast {
+            text.push_str(" contrast");
+        }
+        text
+    }
+}
+
+fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let text = text.trim_left_matches('#');
+    // Only hex digits make sure the slices below fall on char boundaries.
+    if text.len() != 6 || !text.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&text[i..i + 2], 16).ok();
+    match (channel(0), channel(2), channel(4)) {
+        (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+        _ => None,
+    }
+}
+
+fn format_color(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+pub fn template(name: &str) -> Option<(&'static str, &'static str)> {
+    Some(match name {
+             "normal" => ("normal", include_str!("../bingo-templates/botw.json")),
+             "korok" => ("korok", include_str!("../bingo-templates/botw-korok.json")),
+             "shrine" => ("shrine", include_str!("../bingo-templates/botw-shrine.json")),
+             "gp" | "plateau" => ("plateau", include_str!("../bingo-templates/botw-plateau.json")),
+             "compendium" => {
+                 ("compendium", include_str!("../bingo-templates/botw-compendium.json"))
+             }
+             _ => return None,
+         })
+}
+
+/// The built in bingo templates and the ones loaded from the template
+/// directory, which take precedence.
+#[derive(Default)]
+pub struct Templates {
+    custom: Vec<(&'static str, &'static str)>,
+}
+
+impl Templates {
+    /// Loads every `<name>.json` template in the directory. Templates that
+    /// can't be read or parsed are skipped.
+    pub fn load<P: AsRef<Path>>(directory: P) -> Templates {
+        let mut templates = Templates::default();
+        let entries = match fs::read_dir(directory.as_ref()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Couldn't read the bingo template directory {}: {}",
+                       directory.as_ref().display(),
+                       e);
+                return templates;
+            }
+        };
+
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if path.extension().map_or(true, |e| e != "json") {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|n| n.to_str()) {
+                Some(name) => name.to_lowercase(),
+                None => continue,
+            };
+            let mut source = String::new();
+            if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut source)) {
+                warn!("Couldn't read the bingo template {}: {}", path.display(), e);
+                continue;
+            }
+            if let Err(e) = generate(&source, 0, Mode::Normal) {
+                warn!("Skipping the bingo template {}: {}", path.display(), e);
+                continue;
+            }
+            info!("Loaded the bingo template {}", name);
+            templates.custom.push((leak(name), leak(source)));
+        }
+
+        templates
+    }
+
+    /// Looks up the template's name and JSON.
+    pub fn get(&self, name: &str) -> Option<(&'static str, &'static str)> {
+        self.custom
+            .iter()
+            .find(|&&(custom, _)| custom == name)
+            .cloned()
+            .or_else(|| template(name))
+    }
+}
+
+/// Templates are loaded once on startup and live for the rest of the program,
+/// so they can be referred to just like the built in ones.
+fn leak(text: String) -> &'static str {
+    unsafe { &*Box::into_raw(text.into_boxed_str()) }
+}
+
+pub fn mode(name: &str) -> Option<(Mode, &'static str)> {
+    Some(match name {
+             "normal" => (Mode::Normal, "normal"),
+             "short" => (Mode::Short, "short"),
+             "long" => (Mode::Long, "long"),
+             _ => return None,
+         })
+}
+
+impl Variant {
+    pub fn from_params<S: AsRef<str>>(params: &[S], templates: &Templates) -> Variant {
+        let (template, source) = templates.get("normal").unwrap();
+        let mut variant = Variant {
+            template: template,
+            source: source,
+            mode: "normal",
+            lockout: false,
+        };
+
+        for param in params {
+            let param = param.as_ref().to_lowercase();
+            if param == "lockout" {
+                variant.lockout = true;
+            } else if let Some((_, mode)) = mode(&param) {
+                variant.mode = mode;
+            } else if let Some((name, source)) = templates.get(&param) {
+                variant.template = name;
+                variant.source = source;
+            }
+        }
+
+        variant
+    }
+
+    pub fn generate(&self, seed: u32) -> Result<Board, Error> {
+        match mode(self.mode) {
+            Some((mode, _)) => generate(self.source, seed, mode),
+            None => Err(Error::Internal(String::from("Unknown bingo variant"))),
+        }
+    }
+
+    pub fn url(&self, public_url: &str, seed: u32) -> String {
+        format!("{}/botw/bingo/{}/index.html?seed={}&mode={}",
+                public_url,
+                self.template,
+                seed,
+                self.mode)
+    }
+}
+
+impl BingoGame {
+    pub fn new(variant: Variant, seed: u32, players: Vec<u64>) -> Result<BingoGame, Error> {
+        let board = variant.generate(seed)?;
+        Ok(BingoGame {
+               variant: variant,
+               seed: seed,
+               board: board,
+               players: players,
+               marks: HashMap::new(),
+           })
+    }
+
+    pub fn goal(&self, cell: usize) -> &str {
+        &self.board.0[cell].name
+    }
+
+    /// Looks up a cell either by its 1-based `<row> <col>` coordinates or by
+    /// the name of its goal.
+    pub fn find_cell<S: AsRef<str>>(&self, params: &[S]) -> Option<usize> {
+        if params.len() == 2 {
+            if let (Ok(row), Ok(col)) = (params[0].as_ref().parse::<usize>(),
+                                         params[1].as_ref().parse::<usize>()) {
+                if row >= 1 && row <= 5 && col >= 1 && col <= 5 {
+                    return Some((row - 1) * 5 + col - 1);
+                }
+                return None;
+            }
+        }
+
+        let name = params
+            .iter()
+            .map(|p| p.as_ref())
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase();
+
+        (0..25).find(|&cell| self.goal(cell).to_lowercase() == name)
+    }
+
+    /// Marks the cell for the entrant. In lockout bingo a cell can't be
+    /// marked if another entrant already claimed it.
+    pub fn mark(&mut self, entrant: u64, cell: usize) -> Mark {
+        if self.variant.lockout {
+            if let Some(owner) = self.owner(cell) {
+                return if owner == entrant {
+                           Mark::AlreadyMarked
+                       } else {
+                           Mark::ClaimedBy(owner)
+                       };
+            }
+        }
+
+        let marks = self.marks.entry(entrant).or_insert([false; 25]);
+        if marks[cell] {
+            Mark::AlreadyMarked
+        } else {
+            marks[cell] = true;
+            Mark::Marked
+        }
+    }
+
+    fn owner(&self, cell: usize) -> Option<u64> {
+        self.marks
+            .iter()
+            .find(|&(_, marks)| marks[cell])
+            .map(|(&entrant, _)| entrant)
+    }
+
+    pub fn marked_cells(&self, entrant: u64) -> usize {
+        self.marks
+            .get(&entrant)
+            .map_or(0, |marks| marks.iter().filter(|&&marked| marked).count())
+    }
+
+    /// An entrant wins by completing a row, column or diagonal. In lockout
+    /// bingo holding a majority of the cells wins as well.
+    pub fn has_won(&self, entrant: u64) -> bool {
+        let has_line = match self.marks.get(&entrant) {
+            Some(marks) => LINES.iter().any(|line| line.iter().all(|&cell| marks[cell])),
+            None => false,
+        };
+        has_line || (self.variant.lockout && self.marked_cells(entrant) > 25 / 2)
+    }
+
+    pub fn color(&self, entrant: u64) -> Option<(&'static str, [u8; 3])> {
+        self.players
+            .iter()
+            .position(|&p| p == entrant)
+            .map(|index| PLAYER_COLORS[index % PLAYER_COLORS.len()])
+    }
+
+    pub fn render_png(&self, options: &RenderOptions) -> Result<Vec<u8>, Error> {
+        let mut highlights = [None; 25];
+        if self.variant.lockout {
+            for (cell, highlight) in highlights.iter_mut().enumerate() {
+                *highlight = self.owner(cell)
+                    .and_then(|owner| self.color(owner))
+                    .map(|(_, color)| color);
+            }
+        }
+        render_png(&self.board, &highlights, options)
+    }
+}
+
+pub fn generate(template: &str, seed: u32, mode: Mode) -> Result<Board, Error> {
+    let template = Template::from_json_str(template)
+        .map_err(|e| Error::Internal(format!("Couldn't parse the bingo template: {:?}", e)))?;
+    Ok(template.generate(seed, mode))
+}
+
+/// Renders the board, tinting the cells that have a highlight color, such as
+/// the cells claimed in lockout bingo.
+pub fn render_png(board: &Board,
+                  highlights: &[Option<[u8; 3]>],
+                  options: &RenderOptions)
+                  -> Result<Vec<u8>, Error> {
+    let mut image = speedrun_bingo::render(board,
+                                           options.cell_size,
+                                           options.cell_padding,
+                                           options.font.bytes(),
+                                           options.font_size);
+
+    recolor(&mut image, options);
+
+    for (cell, highlight) in highlights.iter().enumerate() {
+        if let Some(color) = *highlight {
+            highlight_cell(&mut image, cell, color);
+        }
+    }
+
+    let mut buffer = Vec::new();
+    PNGEncoder::new(&mut buffer)
+        .encode(&image, image.width(), image.height(), ColorType::RGBA(8))
+        .map_err(|e| Error::Internal(format!("Couldn't encode the bingo board: {}", e)))?;
+    Ok(buffer)
+}
+
+/// Maps the rendered board onto the configured colors. Every pixel is
+/// interpolated between the background and the text color based on how much
+/// it differs from the original background, so anti-aliasing is preserved.
+fn recolor(image: &mut RgbaImage, options: &RenderOptions) {
+    let background = if options.high_contrast {
+        Some([0xff, 0xff, 0xff])
+    } else {
+        options.background
+    };
+
+    if let Some(background) = background {
+        let luminance = |p: &[u8]| (0.299 * p[0] as f32 + 0.587 * p[1] as f32 +
+                                    0.114 * p[2] as f32) / 255.0;
+        let text = if luminance(&background) > 0.5 {
+            [0x00, 0x00, 0x00]
+        } else {
+            [0xff, 0xff, 0xff]
+        };
+
+        let padding = options.cell_padding as u32 + 1;
+        let original = luminance(&image.get_pixel(padding, padding).data);
+        let range = if original > 0.5 { original } else { 1.0 - original };
+
+        for pixel in image.pixels_mut() {
+            let mut amount = ((luminance(&pixel.data) - original).abs() / range).min(1.0);
+            if options.high_contrast {
+                amount = if amount > 0.5 { 1.0 } else { 0.0 };
+            }
+            for i in 0..3 {
+                pixel.data[i] = (background[i] as f32 * (1.0 - amount) +
+                                 text[i] as f32 * amount) as u8;
+            }
+        }
+    }
+
+    let border = if options.high_contrast {
+        options.border.or(Some([0x00, 0x00, 0x00]))
+    } else {
+        options.border
+    };
+
+    if let Some(border) = border {
+        let mut width = cmp::max(1, options.cell_size / 75);
+        if options.high_contrast {
+            width *= 2;
+        }
+        let (image_width, image_height) = (image.width(), image.height());
+        let (cell_width, cell_height) = (image_width / 5, image_height / 5);
+
+        for y in 0..image_height {
+            for x in 0..image_width {
+                let on_border = x % cell_width < width || cell_width - x % cell_width <= width ||
+                                y % cell_height < width ||
+                                cell_height - y % cell_height <= width;
+                if on_border {
+                    let pixel = image.get_pixel_mut(x, y);
+                    pixel.data = [border[0], border[1], border[2], 0xff];
+                }
+            }
+        }
+    }
+}
+
+fn highlight_cell(image: &mut RgbaImage, cell: usize, color: [u8; 3]) {
+    let (cell_width, cell_height) = (image.width() / 5, image.height() / 5);
+    let (x, y) = ((cell as u32 % 5) * cell_width, (cell as u32 / 5) * cell_height);
+
+    for py in y..y + cell_height {
+        for px in x..x + cell_width {
+            let pixel = image.get_pixel_mut(px, py);
+            for (channel, &tint) in pixel.data.iter_mut().zip(color.iter()) {
+                *channel = (*channel as f32 * (1.0 - HIGHLIGHT_OPACITY) +
+                            tint as f32 * HIGHLIGHT_OPACITY) as u8;
+            }
+        }
+    }
+}
+
+pub fn to_json(board: &Board, template: &str, seed: u32, mode: &str) -> BoardJson {
+    let goals = board.0.iter().map(|g| g.name.clone()).collect::<Vec<_>>();
+
+    BoardJson {
+        template: template.to_owned(),
+        seed: seed,
+        mode: mode.to_owned(),
+        goals: goals.chunks(5).map(|row| row.to_vec()).collect(),
+    }
+}
+
+#[test]
+fn verify_bingo_board() {
+    use rand::{Rng, thread_rng};
+
+    let (_, template) = template("normal").unwrap();
+    let template = Template::from_json_str(template).unwrap();
+
+    let mut rng = thread_rng();
+    let seed = rng.gen_range(0, 1_000_000);
+
+    let board = template.generate(seed, Mode::Short);
+
+    let options = RenderOptions::default();
+    let image = speedrun_bingo::render(&board,
+                                       options.cell_size,
+                                       options.cell_padding,
+                                       options.font.bytes(),
+                                       options.font_size);
+    image.save("test.png");
+
+    let mut goals = template
+        .0
+        .iter()
+        .flat_map(|r| r.iter())
+        .map(|g| &g.name)
+        .collect::<Vec<_>>();
+    goals.sort();
+
+    for (a, b) in goals.iter().zip(goals.iter().skip(1)) {
+        assert_ne!(a, b);
+    }
+}
+
+#[test]
+fn lockout_claims() {
+    let variant = Variant::from_params(&["lockout"], &Templates::default());
+    let mut game = BingoGame::new(variant, 1234, vec![1, 2]).unwrap();
+
+    assert!(match game.mark(1, 0) {
+                Mark::Marked => true,
+                _ => false,
+            });
+    assert!(match game.mark(2, 0) {
+                Mark::ClaimedBy(1) => true,
+                _ => false,
+            });
+
+    // A majority of scattered cells wins without completing a line.
+    for &cell in &[1, 2, 3, 5, 7, 9, 11, 13, 15, 17, 21, 23] {
+        assert!(!game.has_won(1));
+        game.mark(1, cell);
+    }
+    assert!(game.has_won(1));
+    assert!(!game.has_won(2));
+}
+
+#[test]
+fn render_options() {
+    let mut options = RenderOptions::default();
+    options.apply_params(&["short", "size=200", "font=fira", "bg=#102030", "contrast"]);
+
+    assert_eq!(options.cell_size, 200);
+    assert!(options.font == Font::FiraSans);
+    assert_eq!(options.background, Some([0x10, 0x20, 0x30]));
+    assert!(options.high_contrast);
+
+    assert!(!options.apply("size=10000"));
+    assert_eq!(options.cell_size, 200);
+}
+
+#[test]
+fn parse_colors() {
+    assert_eq!(parse_color("#102030"), Some([0x10, 0x20, 0x30]));
+    assert_eq!(parse_color("FFffFF"), Some([0xff, 0xff, 0xff]));
+    assert_eq!(parse_color("#12345g"), None);
+    assert_eq!(parse_color("a\u{e9}\u{20ac}"), None);
+    assert!(!RenderOptions::default().apply("bg=a\u{e9}\u{20ac}"));
+}
@@ -0,0 +1,2933 @@
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::{Read, Cursor};
+use std::mem;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use {FIRST_IDENTITY, LSState, PendingLink, User};
+use race::{self, AsyncRace, Entrant, Race, RaceSetup, RaceInProgress, TeamMode, format_time};
+use chashmap::WriteGuard;
+use hyper_rustls::TlsClient;
+use hyper::Client as HyperClient;
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use livesplit_core::{Color, Run, Segment, Timer};
+use livesplit_core::parser::composite;
+use rand::{Rng, thread_rng};
+use bingo::{self, BingoGame, Mark, Variant};
+#[cfg(test)]
+use config::Config;
+use error::Error;
+use history::{self, EntrantRecord, RaceRecord, TeamRecord};
+use {irc, settings};
+
+/// The user that invoked a command and where they invoked it.
+pub struct Invoker {
+    pub id: u64,
+    pub name: String,
+    pub channel: u64,
+    pub guild: u64,
+    /// Whether the user may manage the guild, like the owner of a Discord
+    /// server or a Twitch broadcaster.
+    pub manage_guild: bool,
+    /// The names of the user's roles in the guild.
+    pub roles: Vec<String>,
+}
+
+/// How many seconds to wait between checking whether the race timed out.
+const TIMEOUT_INTERVAL: u64 = 30;
+
+/// How far ahead a race can be scheduled, in seconds. There is only one race
+/// at a time, so a scheduled race can't hold up everyone else for long.
+const MAX_SCHEDULE_LEAD_TIME: u64 = 3 * 3600;
+
+/// The commands that can only be used in the guild's race channels.
+const RACE_COMMANDS: [&'static str; 22] = ["create-race",
+                                          "schedule-race",
+                                          "create-async",
+                                          "async-start",
+                                          "async-done",
+                                          "async-forfeit",
+                                          "async-races",
+                                          "cancel-race",
+                                          "kick",
+                                          "force-start",
+                                          "close-entry",
+                                          "entrants",
+                                          "enter",
+                                          "ready",
+                                          "unready",
+                                          "leave",
+                                          "watch",
+                                          "unwatch",
+                                          "done",
+                                          "mark",
+                                          "load-race-splits",
+                                          "bingo"];
+
+pub struct Embed {
+    pub title: String,
+    pub description: String,
+    pub colour: (u8, u8, u8),
+    pub fields: Vec<(String, String)>,
+}
+
+pub enum Reply {
+    Text(String),
+    Embed(Embed),
+    File {
+        name: String,
+        content: Vec<u8>,
+        text: String,
+    },
+    /// A message that only the user with the given ID should see.
+    Direct(u64, String),
+    /// The current standings of the race with the given ID. The frontend
+    /// keeps a single message per race that it updates with these, until the
+    /// race is finished or got cancelled.
+    RaceStatus {
+        race: u64,
+        embed: Embed,
+        finished: bool,
+    },
+    /// All the entrants are ready. The frontend announces it, counts down the
+    /// seconds and then calls `start_race`. Counting down blocks, so it comes
+    /// last, see `countdowns_last`.
+    Countdown(u64),
+    /// The user starts their run of the async race with the given ID. The
+    /// frontend counts down the seconds and then calls `start_async_run`.
+    AsyncCountdown { race: u64, seconds: u64 },
+}
+
+pub type CommandResult = Result<Vec<Reply>, Error>;
+
+fn is_countdown(reply: &Reply) -> bool {
+    match *reply {
+        Reply::Countdown(_) |
+        Reply::AsyncCountdown { .. } => true,
+        _ => false,
+    }
+}
+
+/// Moves the direct messages behind the replies everyone gets to see, as they
+/// don't arrive if the user doesn't accept them.
+fn directs_last(mut replies: Vec<Reply>) -> Vec<Reply> {
+    replies.sort_by_key(|reply| match *reply {
+                            Reply::Direct(..) => true,
+                            _ => false,
+                        });
+    replies
+}
+
+/// Moves the countdowns behind the other replies. The frontends block while
+/// counting down, and everything else should be delivered before that.
+pub fn countdowns_last(mut replies: Vec<Reply>) -> Vec<Reply> {
+    replies.sort_by_key(is_countdown);
+    replies
+}
+
+impl Embed {
+    /// Renders the embed for frontends that can only show plain text.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n{}", self.title, self.description.trim().replace("**", ""));
+        for &(ref name, ref value) in &self.fields {
+            write!(text, "\n{}  {}", name, value).unwrap();
+        }
+        text
+    }
+}
+
+type Handler = fn(&Invoker, Vec<String>, &LSState) -> CommandResult;
+
+fn text<S: Into<String>>(text: S) -> CommandResult {
+    Ok(vec![Reply::Text(text.into())])
+}
+
+/// Splits a message into the command and its parameters if it starts with
+/// the prefix.
+pub fn parse(prefix: &str, message: &str) -> Option<(String, Vec<String>)> {
+    if !message.starts_with(prefix) {
+        return None;
+    }
+    let mut words = message[prefix.len()..].split_whitespace();
+    words
+        .next()
+        .map(|command| {
+                 (command.to_lowercase(), words.map(String::from).collect())
+             })
+}
+
+/// Executes the command. Returns `None` if there is no such command or it is
+/// disabled.
+pub fn execute(state: &LSState,
+               invoker: &Invoker,
+               command: &str,
+               params: Vec<String>)
+               -> Option<CommandResult> {
+    if !state.config.is_enabled(command) {
+        return None;
+    }
+    if RACE_COMMANDS.iter().any(|&c| c == command) &&
+       !state
+            .guild_settings(invoker.guild)
+            .allows_races_in(invoker.channel) {
+        return Some(Err(Error::RaceChannel));
+    }
+    let handler: Handler = match command {
+        "split" => split,
+        "reset" => reset,
+        "timer" => get_state,
+        "load-splits" => load_splits,
+        "load-race-splits" => load_race_splits,
+        "create-race" => create_race,
+        "schedule-race" => schedule_race,
+        "entrants" => entrants,
+        "enter" => enter,
+        "ready" => ready,
+        "done" => done,
+        "mark" => mark,
+        "bingo" => create_bingo,
+        "bingo-style" => bingo_style,
+        "server-token" => server_token,
+        "link" => link,
+        "verify" => verify,
+        "config" => config,
+        "cancel-race" => cancel_race,
+        "kick" => kick,
+        "force-start" => force_start,
+        "close-entry" => close_entry,
+        "leave" => leave,
+        "race-history" => race_history,
+        "race" => race_details,
+        "race-splits" => race_splits,
+        "race-record" => race_record,
+        "rating" => rating,
+        "leaderboard" => leaderboard,
+        "unready" => unready,
+        "watch" => watch,
+        "unwatch" => unwatch,
+        "create-async" => create_async,
+        "async-start" => async_start,
+        "async-done" => async_done,
+        "async-forfeit" => async_forfeit,
+        "async-races" => async_races,
+        _ => return None,
+    };
+    Some(handler(invoker, params, state).map(directs_last))
+}
+
+fn layout(user: &mut User) -> Embed {
+    let layout = user.eval_layout();
+    let colour = match layout.timer.color {
+        Color::AheadGainingTime => (0x00, 0xcc, 0x4b),
+        Color::AheadLosingTime => (0x5c, 0xd6, 0x89),
+        Color::BehindGainingTime => (0xd6, 0x5c, 0x5c),
+        Color::BehindLosingTime => (0xcc, 0x00, 0x00),
+        Color::BestSegment => (0xff, 0xd4, 0x00),
+        Color::NotRunning | Color::Default => (0x99, 0x99, 0x99),
+        Color::Paused => (0x66, 0x66, 0x66),
+        Color::PersonalBest => (0x4d, 0xa6, 0xff),
+    };
+
+    Embed {
+        title: format!("{} - {}", layout.title.game, layout.title.category),
+        description: format!(r"
+**{}{}**
+
+**{}:**   {}
+**{}:**   {}
+**{}:**   {}
+**Attempts:**   {}",
+                             layout.timer.time,
+                             layout.timer.fraction,
+                             layout.previous_segment.text,
+                             layout.previous_segment.time,
+                             layout.sum_of_best.text,
+                             layout.sum_of_best.time,
+                             layout.possible_time_save.text,
+                             layout.possible_time_save.time,
+                             layout.title.attempts),
+        colour: colour,
+        fields: layout
+            .splits
+            .splits
+            .iter()
+            .map(|segment| {
+                     (segment.name.clone(), format!("{}  {}", segment.delta, segment.time))
+                 })
+            .collect(),
+    }
+}
+
+fn user<'a>(state: &'a LSState, invoker: &Invoker) -> WriteGuard<'a, u64, User> {
+    state.user(invoker.id, invoker.name.as_str())
+}
+
+fn name(state: &LSState, id: u64) -> Result<String, Error> {
+    state
+        .users
+        .get(&id)
+        .map(|user| user.name.clone())
+        .ok_or(Error::UserNotFound(id))
+}
+
+/// Whether the user's latest split was their best segment yet.
+fn got_gold(user: &mut User) -> bool {
+    match user.eval_layout().previous_segment.color {
+        Color::BestSegment => true,
+        _ => false,
+    }
+}
+
+fn split(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let (mut replies, gold) = {
+        let mut user = user(state, invoker);
+        user.timer.split();
+        (vec![Reply::Embed(layout(&mut user))], got_gold(&mut user))
+    };
+    record_split(state, invoker.id, gold, &mut replies)?;
+    Ok(replies)
+}
+
+/// Records the split of the user's timer in the races they run in. The user
+/// needs to be unlocked, as the race status looks up every entrant.
+fn record_split(state: &LSState,
+                id: u64,
+                gold: bool,
+                replies: &mut Vec<Reply>)
+                -> Result<(), Error> {
+    for async_race in state.async_races.write().iter_mut() {
+        async_race.race.split(id);
+    }
+    let mut race = state.race.write();
+    let racing = match *race {
+        Race::InProgress(ref mut in_progress) => {
+            let racing = in_progress.split(id);
+            if racing && gold {
+                let splits = in_progress
+                    .entrants
+                    .iter()
+                    .find(|e| e.id == id)
+                    .map_or(0, |e| e.splits.len());
+                notify_watchers(in_progress,
+                                format!("{} got a gold on split {} of race #{}!",
+                                        name(state, id)?,
+                                        splits,
+                                        in_progress.id),
+                                replies);
+            }
+            racing
+        }
+        _ => false,
+    };
+    if racing {
+        update_race(state, &mut race, replies)?;
+    }
+    Ok(())
+}
+
+/// Records the split of a timer that got split outside of a chat, like by
+/// LiveSplit or an autosplitter. What happens in the race is announced in its
+/// channel.
+pub fn announce_split(state: &LSState, id: u64) {
+    let (name, gold) = match state.users.get_mut(&id) {
+        Some(mut user) => (user.name.clone(), got_gold(&mut user)),
+        None => return,
+    };
+    let channel = match *state.race.read() {
+        Race::InProgress(ref race) => race.channel,
+        _ => 0,
+    };
+
+    let mut replies = Vec::new();
+    if let Err(e) = record_split(state, id, gold, &mut replies) {
+        error!("Couldn't record the split of {}: {}", name, e);
+    }
+    // Only the channel matters for what a split announces.
+    let invoker = Invoker {
+        id: id,
+        name: name,
+        channel: channel,
+        guild: 0,
+        manage_guild: false,
+        roles: Vec::new(),
+    };
+    announce_replies(state, &invoker, replies);
+}
+
+/// Sends everyone watching the race a direct message.
+fn notify_watchers(race: &RaceInProgress, text: String, replies: &mut Vec<Reply>) {
+    for &watcher in &race.watchers {
+        replies.push(Reply::Direct(watcher, text.clone()));
+    }
+}
+
+fn reset(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut user = user(state, invoker);
+    user.timer.reset(true);
+    Ok(vec![Reply::Embed(layout(&mut user))])
+}
+
+fn get_state(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut user = user(state, invoker);
+    Ok(vec![Reply::Embed(layout(&mut user))])
+}
+
+fn load_race_splits(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let (creator, game, category) = match *state.race.read() {
+        Race::NoRace => return Err(Error::NoRace),
+        Race::Countdown(_) |
+        Race::InProgress(_) => return Err(Error::RaceInProgress),
+        Race::Setup(ref setup) => (setup.creator, setup.game.clone(), setup.category.clone()),
+    };
+
+    // The creator's timer needs to be cloned before locking the invoking
+    // user, as they may be the same user.
+    let timer = {
+        let creator_user = state.users.get(&creator).ok_or(Error::UserNotFound(creator))?;
+        let run = creator_user.timer.run();
+        let matches = |name: &Option<String>, current: &str| {
+            name.as_ref().map_or(true, |name| name.eq_ignore_ascii_case(current))
+        };
+        if !matches(&game, run.game_name()) || !matches(&category, run.category_name()) {
+            // The creator didn't load splits for the race, so everyone
+            // gets a run with just the race's game and category.
+            let mut race_run = Run::new();
+            race_run.push_segment(Segment::new("End"));
+            race_run.set_game_name(game.as_ref().map_or(run.game_name(), |g| g.as_str()));
+            race_run.set_category_name(category
+                                           .as_ref()
+                                           .map_or(run.category_name(), |c| c.as_str()));
+            Some(Timer::new(race_run))
+        } else if creator != invoker.id {
+            Some(creator_user.timer.clone())
+        } else {
+            None
+        }
+    };
+
+    let mut user = user(state, invoker);
+    if let Some(timer) = timer {
+        user.timer = timer;
+    }
+    Ok(vec![Reply::Embed(layout(&mut user))])
+}
+
+fn load_splits(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let id = params.get(0).ok_or(Error::Usage("!load-splits <splits.io ID>"))?;
+    let ssl = TlsClient::new();
+    let connector = HttpsConnector::new(ssl);
+    let client = HyperClient::with_connector(connector);
+    let mut response = client
+        .get(&format!("https://splits.io/{}/download/livesplit", id))
+        .send()
+        .map_err(|e| Error::Transport(format!("Couldn't reach splits.io: {}", e)))?;
+    if response.status != StatusCode::Ok {
+        return Err(Error::SplitsNotFound(id.clone()));
+    }
+    let mut splits = Vec::new();
+    response
+        .read_to_end(&mut splits)
+        .map_err(|e| Error::Transport(format!("Couldn't download the splits: {}", e)))?;
+    let run = composite::parse(Cursor::new(splits), None, false)
+        .map_err(|_| Error::SplitsNotFound(id.clone()))?;
+
+    let mut user = user(state, invoker);
+    user.timer = Timer::new(run);
+    Ok(vec![Reply::Embed(layout(&mut user))])
+}
+
+/// Generates a new token for authenticating with the LiveSplit Server
+/// protocol, replacing the user's previous token.
+fn server_token(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    user(state, invoker); // Make sure the user exists
+    let token = thread_rng().gen_ascii_chars().take(24).collect::<String>();
+
+    let mut tokens = state.tokens.write();
+    tokens.retain(|_, &mut id| id != invoker.id);
+    tokens.insert(token.clone(), invoker.id);
+
+    Ok(vec![Reply::Text(String::from("Sent you a LiveSplit Server token.")),
+            Reply::Direct(invoker.id,
+                          format!("Connect to the LiveSplit Server at `{}` and send `auth \
+                                   {}` to control your timer. It can also be controlled \
+                                   through `{}/timer/{}/split`.",
+                                  state.config.livesplit_server_address,
+                                  token,
+                                  state.config.public_url,
+                                  token))])
+}
+
+fn service(name: &str) -> Result<&'static str, Error> {
+    match name.to_lowercase().as_str() {
+        "twitch" => Ok(irc::TWITCH_SERVICE),
+        _ => Err(Error::UnknownService(name.to_owned())),
+    }
+}
+
+/// Starts linking an identity of another frontend to the user. The link only
+/// takes effect once the code is sent back from that identity with
+/// `!verify`.
+fn link(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    const USAGE: &'static str = "!link twitch <name>";
+    let service = service(params.get(0).ok_or(Error::Usage(USAGE))?)?;
+    let name = params.get(1).ok_or(Error::Usage(USAGE))?.to_lowercase();
+    user(state, invoker); // Make sure the user exists
+
+    let code = thread_rng().gen_ascii_chars().take(8).collect::<String>();
+    let mut pending_links = state.pending_links.write();
+    pending_links.retain(|_, link| link.user != invoker.id || link.name != name);
+    pending_links.insert(code.clone(),
+                         PendingLink {
+                             user: invoker.id,
+                             service: service,
+                             name: name.clone(),
+                         });
+
+    Ok(vec![Reply::Text(String::from("Sent you a verification code.")),
+            Reply::Direct(invoker.id,
+                          format!("Send `!verify {}` as {} on {} to link it to your account.",
+                                  code,
+                                  name,
+                                  params[0]))])
+}
+
+fn verify(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let code = params.get(0).ok_or(Error::Usage("!verify <code>"))?;
+
+    let link = {
+        let mut pending_links = state.pending_links.write();
+        let is_valid = pending_links
+            .get(code)
+            .map_or(false, |link| {
+                state.identities.read().get(&(link.service, link.name.clone())) ==
+                Some(&invoker.id)
+            });
+        if !is_valid {
+            return Err(Error::InvalidVerificationCode);
+        }
+        pending_links.remove(code).unwrap()
+    };
+
+    let owner = name(state, link.user)?;
+    if let Some(previous) = state.link_identity(link.service, &link.name, link.user) {
+        // The identity's own timer is replaced by the linked user's timer.
+        // Accounts that were linked before and entrants of a race are kept.
+        if previous != link.user && previous >= FIRST_IDENTITY && !is_racing(state, previous) {
+            state.users.remove(&previous);
+            state.tokens.write().retain(|_, &mut id| id != previous);
+        }
+    }
+
+    text(format!("Linked {} to the account of {}.", link.name, owner))
+}
+
+/// Whether the user entered the race or one of the open async races.
+fn is_racing(state: &LSState, id: u64) -> bool {
+    let entered = match *state.race.read() {
+        Race::NoRace => false,
+        Race::Setup(ref setup) |
+        Race::Countdown(ref setup) => setup.entrants.iter().any(|&(entrant, _)| entrant == id),
+        Race::InProgress(ref race) => race.entrants.iter().any(|e| e.id == id),
+    };
+    entered ||
+    state
+        .async_races
+        .read()
+        .iter()
+        .any(|r| r.race.entrants.iter().any(|e| e.id == id))
+}
+
+/// Shows or changes the settings of the guild. Only moderators can use it.
+fn config(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    const USAGE: &'static str = "!config get [setting] | !config set <setting> [value]";
+    let mut guild_settings = state.guild_settings.write();
+    let mut settings = guild_settings
+        .get(&invoker.guild)
+        .cloned()
+        .unwrap_or_default();
+
+    if !invoker.manage_guild && !settings.is_moderator(&invoker.roles) {
+        return Err(Error::NotModerator);
+    }
+
+    let action = params.get(0).map(|a| a.to_lowercase());
+    let key = params.get(1).map(|k| k.to_lowercase());
+    match (action.as_ref().map(|a| a.as_str()), key) {
+        (Some("get"), None) |
+        (None, None) => {
+            let mut message = String::from("Settings:");
+            for key in &settings::KEYS {
+                write!(message, "\n{}: {}", key, settings.get(key).unwrap()).unwrap();
+            }
+            text(message)
+        }
+        (Some("get"), Some(key)) => {
+            let value = settings.get(&key).ok_or(Error::UnknownSetting(key.clone()))?;
+            text(format!("{}: {}", key, value))
+        }
+        (Some("set"), Some(key)) => {
+            settings.set(&key, &params[2..], invoker.channel, &state.bingo_templates)?;
+            let value = settings.get(&key).unwrap();
+            guild_settings.insert(invoker.guild, settings);
+            settings::save(&state.config.storage_path, &guild_settings)?;
+            text(format!("Changed {} to {}", key, value))
+        }
+        _ => Err(Error::Usage(USAGE)),
+    }
+}
+
+fn create_race(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    new_race(invoker, params, state, None)
+}
+
+/// Sets up a race that starts later. It holds the race until then, so only
+/// organizers can schedule races.
+fn schedule_race(invoker: &Invoker, mut params: Vec<String>, state: &LSState) -> CommandResult {
+    if !invoker.manage_guild &&
+       !state
+            .guild_settings(invoker.guild)
+            .is_organizer(&invoker.roles) {
+        return Err(Error::NotOrganizer);
+    }
+    let now = history::now();
+    let start_at = if params.is_empty() {
+        None
+    } else {
+        race::parse_start_time(&params.remove(0), now)
+    };
+    match start_at {
+        // The race blocks every other race until it starts.
+        Some(start_at) if start_at - now > MAX_SCHEDULE_LEAD_TIME => {
+            Err(Error::TooFarAhead(MAX_SCHEDULE_LEAD_TIME / 3600))
+        }
+        Some(start_at) => new_race(invoker, params, state, Some(start_at)),
+        None => {
+            Err(Error::Usage("!schedule-race <1h30m | HH:MM> [<game> | <category> | <goal>]"))
+        }
+    }
+}
+
+/// Sets up a race, which starts right away once everyone is ready, unless it
+/// is scheduled to start later.
+fn new_race(invoker: &Invoker,
+            params: Vec<String>,
+            state: &LSState,
+            start_at: Option<u64>)
+            -> CommandResult {
+    let mut race = state.race.write();
+    if let Race::NoRace = *race {
+        user(state, invoker); // Make sure the user exists
+        let (team_mode, params) = parse_team_mode(params);
+        let (bingo, mut parts) = parse_race_params(invoker, &params, state)?;
+
+        let kind = format!("{}{}",
+                           match team_mode {
+                               Some(TeamMode::Relay) => "relay ",
+                               Some(_) => "team ",
+                               None => "",
+                           },
+                           if bingo.as_ref().map_or(false, |v| v.lockout) {
+                               "lockout bingo race"
+                           } else if bingo.is_some() {
+                               "bingo race"
+                           } else {
+                               "race"
+                           });
+        let response = match start_at {
+            Some(start_at) => {
+                format!("Scheduled a new {} for {}!",
+                        kind,
+                        history::format_date_time(start_at))
+            }
+            None => format!("Created a new {}!", kind),
+        };
+        let setup = RaceSetup {
+            id: state.next_race_id(),
+            creator: invoker.id,
+            // The creator picks their team like everyone else in a team
+            // race.
+            entrants: if team_mode.is_some() {
+                Vec::new()
+            } else {
+                vec![(invoker.id, false)]
+            },
+            team_mode: team_mode,
+            teams: HashMap::new(),
+            game: parts[0].take(),
+            category: parts[1].take(),
+            goal: parts[2].take(),
+            bingo: bingo,
+            closed: false,
+            channel: invoker.channel,
+            guild: invoker.guild,
+            created: Instant::now(),
+            start_at: start_at,
+            reminded: false,
+            watchers: Vec::new(),
+        };
+        let mut response = match setup.title() {
+            Some(title) => format!("{}\n{}", response, title),
+            None => response,
+        };
+        if team_mode.is_some() {
+            write!(response,
+                   "\nJoin a team with `{}enter team <name>`.",
+                   state.prefix(invoker.guild))
+                    .unwrap();
+        }
+        *race = Race::Setup(setup);
+        text(response)
+    } else {
+        Err(Error::RaceAlreadyExists)
+    }
+}
+
+/// Parses what the race is about, which is either a bingo variant or
+/// `<game> | <category> | <goal>`, where every part is optional. There are
+/// always three parts.
+fn parse_race_params(invoker: &Invoker,
+                     params: &[String],
+                     state: &LSState)
+                     -> Result<(Option<Variant>, Vec<Option<String>>), Error> {
+    let is_bingo = params
+        .get(0)
+        .map_or(false, |p| {
+            let p = p.to_lowercase();
+            p == "bingo" || p == "lockout"
+        });
+    if is_bingo {
+        let variant = Variant::from_params(params, &state.bingo_templates);
+        check_template(invoker, &variant, state)?;
+        return Ok((Some(variant), vec![None, None, None]));
+    }
+
+    let mut parts = params
+        .join(" ")
+        .splitn(3, '|')
+        .map(|part| part.trim())
+        .map(|part| if part.is_empty() {
+                 None
+             } else {
+                 Some(part.to_owned())
+             })
+        .collect::<Vec<_>>();
+    parts.resize(3, None);
+    Ok((None, parts))
+}
+
+/// Takes `relay`, `team best` or `team sum` off the front of the parameters.
+/// Teams race for their best time unless they say otherwise.
+fn parse_team_mode(mut params: Vec<String>) -> (Option<TeamMode>, Vec<String>) {
+    let first = params.get(0).map(|p| p.to_lowercase()).unwrap_or_default();
+    if first == "relay" {
+        params.remove(0);
+        return (Some(TeamMode::Relay), params);
+    } else if first != "team" && first != "teams" {
+        return (None, params);
+    }
+    params.remove(0);
+
+    let mode = match params.get(0).map(|p| p.to_lowercase()) {
+        Some(ref mode) if mode == "best" => TeamMode::Best,
+        Some(ref mode) if mode == "sum" => TeamMode::Sum,
+        _ => return (Some(TeamMode::Best), params),
+    };
+    params.remove(0);
+    (Some(mode), params)
+}
+
+fn check_template(invoker: &Invoker, variant: &Variant, state: &LSState) -> Result<(), Error> {
+    if state
+           .guild_settings(invoker.guild)
+           .allows_template(variant.template) {
+        Ok(())
+    } else {
+        Err(Error::TemplateDisabled(variant.template.to_owned()))
+    }
+}
+
+/// Only the race's creator, the moderators and the organizers of the guild
+/// the race was created in can manage the race.
+fn check_organizer(invoker: &Invoker, race: &Race, state: &LSState) -> Result<(), Error> {
+    let (creator, guild) = match (race.creator(), race.guild()) {
+        (Some(creator), Some(guild)) => (creator, guild),
+        _ => return Err(Error::NoRace),
+    };
+    if creator == invoker.id ||
+       invoker.guild == guild &&
+       (invoker.manage_guild ||
+        state
+            .guild_settings(invoker.guild)
+            .is_organizer(&invoker.roles)) {
+        Ok(())
+    } else {
+        Err(Error::NotOrganizer)
+    }
+}
+
+/// Parses a mention like `<@1234>` or a plain user ID.
+fn parse_mention(user: &str) -> Option<u64> {
+    user.trim_left_matches("<@")
+        .trim_left_matches('!')
+        .trim_right_matches('>')
+        .parse()
+        .ok()
+}
+
+/// Finds the entrant by a mention, their ID or their name.
+fn find_entrant(state: &LSState, user: &str, entrants: &[u64]) -> Result<u64, Error> {
+    let id = parse_mention(user);
+    entrants
+        .iter()
+        .cloned()
+        .find(|&entrant| {
+                  Some(entrant) == id ||
+                  name(state, entrant)
+                      .map(|name| name.eq_ignore_ascii_case(user))
+                      .unwrap_or(false)
+              })
+        .ok_or_else(|| Error::EntrantNotFound(user.to_owned()))
+}
+
+fn cancel_race(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    check_organizer(invoker, &race, state)?;
+    let mut replies = Vec::new();
+    if let Race::InProgress(ref in_progress) = *race {
+        replies.push(Reply::RaceStatus {
+                         race: in_progress.id,
+                         embed: race_status(state, in_progress)?,
+                         finished: true,
+                     });
+    }
+    *race = Race::NoRace;
+    replies.push(Reply::Text(String::from("The race got cancelled.")));
+    Ok(replies)
+}
+
+fn kick(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    check_organizer(invoker, &race, state)?;
+    if params.is_empty() {
+        return Err(Error::Usage("!kick <user>"));
+    }
+    let user = params.join(" ");
+
+    let id = match *race {
+        Race::Setup(ref mut setup) => {
+            let entrants = setup.entrants.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+            let id = find_entrant(state, &user, &entrants)?;
+            setup.entrants.retain(|&(entrant, _)| entrant != id);
+            id
+        }
+        Race::InProgress(ref mut in_progress) => {
+            let entrants = in_progress
+                .entrants
+                .iter()
+                .filter(|e| e.finish_time.is_none() && !e.forfeited)
+                .map(|e| e.id)
+                .collect::<Vec<_>>();
+            let id = find_entrant(state, &user, &entrants)?;
+            // Forfeiting hands off to nobody, so the rest of a relay team
+            // doesn't wait forever.
+            in_progress.forfeit(id);
+            id
+        }
+        Race::NoRace => return Err(Error::NoRace),
+        Race::Countdown(_) => return Err(Error::RaceInProgress),
+    };
+
+    // The remaining entrants may all be ready or done by now.
+    let mut replies = vec![Reply::Text(format!("{} got removed from the race.",
+                                               name(state, id)?))];
+    countdown_if_ready(invoker, state, &mut race, &mut replies);
+    update_race(state, &mut race, &mut replies)?;
+    Ok(replies)
+}
+
+/// Starts the countdown even though not every entrant is ready yet.
+fn force_start(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    check_organizer(invoker, &race, state)?;
+    match *race {
+        Race::Setup(ref setup) => {
+            if setup.entrants.is_empty() {
+                return Err(Error::NoEntrants);
+            }
+        }
+        _ => return Err(Error::RaceInProgress),
+    }
+
+    let mut replies = Vec::new();
+    start_countdown(invoker, state, &mut race, &mut replies);
+    Ok(replies)
+}
+
+fn close_entry(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    check_organizer(invoker, &race, state)?;
+    match *race {
+        Race::Setup(ref mut setup) => {
+            setup.closed = true;
+            text("Entry to the race is closed now.")
+        }
+        _ => Err(Error::RaceInProgress),
+    }
+}
+
+fn race_history(_: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let count = params
+        .get(0)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(5);
+    let races = state.history.read();
+    if races.is_empty() {
+        return text("No races were run yet.");
+    }
+
+    let mut message = String::from("Recent races:");
+    for race in races.iter().rev().take(cmp::min(count, 20)) {
+        write!(message,
+               "\n#{} {} {} - {}",
+               race.id,
+               history::format_date(race.date),
+               race.game,
+               race.category)
+                .unwrap();
+        if let Some((winner, time)) = race.winner() {
+            write!(message,
+                   ": won by {} in {}",
+                   winner,
+                   time.map_or_else(String::new, format_time))
+                    .unwrap();
+        }
+        write!(message, " ({} entrants)", race.entrants.len()).unwrap();
+    }
+    text(message)
+}
+
+/// Parses a race ID like `12` or `#12`.
+fn parse_race_id(params: &[String]) -> Option<u64> {
+    params.get(0).and_then(|id| id.trim_left_matches('#').parse().ok())
+}
+
+fn race_details(_: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let id = parse_race_id(&params).ok_or(Error::Usage("!race <id>"))?;
+    let races = state.history.read();
+    let race = races
+        .iter()
+        .find(|race| race.id == id)
+        .ok_or(Error::RaceNotFound(id))?;
+
+    let mut description = history::format_date(race.date);
+    if let Some(ref goal) = race.goal {
+        write!(description, "\n{}", goal).unwrap();
+    }
+    write!(description, "\n\n{}", race.results()).unwrap();
+
+    Ok(vec![Reply::Embed(Embed {
+                             title: race.title(),
+                             description: description,
+                             colour: (0x4d, 0xa6, 0xff),
+                             fields: Vec::new(),
+                         })])
+}
+
+/// Compares the entrants' splits in a past race, with one field per split
+/// that lists the split times and segment times. The fastest segment of
+/// each split is highlighted.
+fn race_splits(_: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let id = parse_race_id(&params).ok_or(Error::Usage("!race-splits <id>"))?;
+    let races = state.history.read();
+    let race = races
+        .iter()
+        .find(|race| race.id == id)
+        .ok_or(Error::RaceNotFound(id))?;
+
+    let segment = |splits: &[Duration], index: usize, time: Duration| {
+        let previous = if index > 0 {
+            splits[index - 1]
+        } else {
+            Duration::from_secs(0)
+        };
+        time.checked_sub(previous).unwrap_or(Duration::from_secs(0))
+    };
+
+    // Every split is a row, with the finish as the last one.
+    let count = race.entrants.iter().map(|e| e.splits.len()).max().unwrap_or(0);
+    let mut rows = Vec::new();
+    for index in 0..count {
+        let times = race.entrants
+            .iter()
+            .filter_map(|e| {
+                            e.splits
+                                .get(index)
+                                .map(|&time| (e, time, segment(&e.splits, index, time)))
+                        })
+            .collect::<Vec<_>>();
+        rows.push((format!("Split {}", index + 1), times));
+    }
+    let finishes = race.entrants
+        .iter()
+        .filter_map(|e| {
+                        e.time
+                            .map(|time| (e, time, segment(&e.splits, e.splits.len(), time)))
+                    })
+        .collect::<Vec<_>>();
+    if !finishes.is_empty() {
+        rows.push((String::from("Finish"), finishes));
+    }
+
+    let fields = rows.into_iter()
+        .map(|(name, times)| {
+            let best = times.iter().map(|&(_, _, segment)| segment).min();
+            let lines = times
+                .iter()
+                .map(|&(entrant, time, segment)| {
+                    let line = format!("{}  {} ({})",
+                                       entrant.name,
+                                       format_time(time),
+                                       format_time(segment));
+                    if Some(segment) == best {
+                        format!("**{}**", line)
+                    } else {
+                        line
+                    }
+                })
+                .collect::<Vec<_>>();
+            (name, lines.join("\n"))
+        })
+        .collect::<Vec<_>>();
+
+    let description = if fields.is_empty() {
+        String::from("Nobody split during the race.")
+    } else {
+        String::from("Split times (segment times), with the fastest segments in bold.")
+    };
+    Ok(vec![Reply::Embed(Embed {
+                             title: race.title(),
+                             description: description,
+                             colour: (0x4d, 0xa6, 0xff),
+                             fields: fields,
+                         })])
+}
+
+/// Sums up the races of the mentioned user, or of the invoker.
+fn race_record(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let user = params.join(" ");
+    let id = if user.is_empty() {
+        Some(invoker.id)
+    } else {
+        parse_mention(&user)
+    };
+
+    let races = state.history.read();
+    let entries = races
+        .iter()
+        .filter_map(|race| {
+            race.entrants
+                .iter()
+                .find(|e| Some(e.id) == id || e.name.eq_ignore_ascii_case(&user))
+        })
+        .collect::<Vec<_>>();
+    let name = match entries.last() {
+        Some(entry) => entry.name.clone(),
+        None if user.is_empty() => invoker.name.clone(),
+        None => user,
+    };
+    if entries.is_empty() {
+        return text(format!("{} didn't run any races yet.", name));
+    }
+
+    let count = |place: usize| entries.iter().filter(|e| e.place == Some(place)).count();
+    text(format!("{}: {} races, {} wins, {} second places, {} third places, {} finished, {} \
+                  forfeited",
+                 name,
+                 entries.len(),
+                 count(1),
+                 count(2),
+                 count(3),
+                 entries.iter().filter(|e| e.place.is_some()).count(),
+                 entries.iter().filter(|e| e.forfeited).count()))
+}
+
+/// Lists the ratings of the mentioned user, or of the invoker, in every
+/// category they raced.
+fn rating(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let user = params.join(" ");
+    let id = if user.is_empty() {
+        Some(invoker.id)
+    } else {
+        parse_mention(&user)
+    };
+
+    let ratings = state.ratings.read();
+    let mut name = if user.is_empty() {
+        invoker.name.clone()
+    } else {
+        user.clone()
+    };
+    let mut lines = Vec::new();
+    for leaderboard in ratings.leaderboards() {
+        let ranking = leaderboard.ranking();
+        let position = ranking
+            .iter()
+            .position(|r| Some(r.id) == id || r.name.eq_ignore_ascii_case(&user));
+        if let Some(position) = position {
+            let rating = ranking[position];
+            name = rating.name.clone();
+            lines.push(format!("{}: {:.0} (#{} of {}, {} races)",
+                               leaderboard.title(),
+                               rating.rating,
+                               position + 1,
+                               ranking.len(),
+                               rating.races));
+        }
+    }
+
+    if lines.is_empty() {
+        text(format!("{} isn't rated yet.", name))
+    } else {
+        text(format!("Ratings of {}:\n{}", name, lines.join("\n")))
+    }
+}
+
+fn leaderboard(_: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    if params.is_empty() {
+        return Err(Error::Usage("!leaderboard <game> <category>"));
+    }
+    let query = params.join(" ");
+    let ratings = state.ratings.read();
+    let leaderboard = match ratings.find(&query) {
+        Some(leaderboard) => leaderboard,
+        None => return text(format!("Nobody is rated in {} yet.", query)),
+    };
+
+    let mut message = format!("**{}**", leaderboard.title());
+    for (index, rating) in leaderboard.ranking().into_iter().take(10).enumerate() {
+        write!(message,
+               "\n{}. {} {:.0} ({} races)",
+               index + 1,
+               rating.name,
+               rating.rating,
+               rating.races)
+                .unwrap();
+    }
+    text(message)
+}
+
+fn create_bingo(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let variant = Variant::from_params(&params, &state.bingo_templates);
+    check_template(invoker, &variant, state)?;
+
+    let mut rng = thread_rng();
+    let seed = rng.gen_range(0, 1_000_000);
+
+    let mut options = state.bingo_style(invoker.guild, variant.template);
+    options.apply_params(&params);
+
+    let board = variant.generate(seed)?;
+    let board_text = variant.url(&state.config.public_url, seed);
+    let image = bingo::render_png(&board, &[], &options)?;
+
+    Ok(vec![Reply::File {
+                name: String::from("bingo.png"),
+                content: image,
+                text: board_text,
+            }])
+}
+
+fn bingo_style(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let guild = invoker.guild;
+    let (template, options) = match params
+              .get(0)
+              .and_then(|p| state.bingo_templates.get(&p.to_lowercase())) {
+        Some((name, _)) => (Some(name), &params[1..]),
+        None => (None, &params[..]),
+    };
+    // Everyone may look at the style, but only moderators change it for the
+    // whole guild.
+    if !options.is_empty() && !invoker.manage_guild &&
+       !state.guild_settings(guild).is_moderator(&invoker.roles) {
+        return Err(Error::NotModerator);
+    }
+
+    let mut guild_settings = state.guild_settings.write();
+    let mut settings = guild_settings.get(&guild).cloned().unwrap_or_default();
+    let mut style = settings.bingo_style(template);
+
+    if options.is_empty() {
+        return text(format!("Bingo style: {}", style.describe()));
+    }
+
+    let response = if options[0].to_lowercase() == "reset" {
+        settings.set_bingo_style(template, None);
+        String::from("The bingo style got reset.")
+    } else {
+        for option in options {
+            if !style.apply(option) {
+                return Err(Error::UnknownRenderOption(option.clone()));
+            }
+        }
+        let response = format!("Updated the bingo style: {}", style.describe());
+        settings.set_bingo_style(template, Some(style));
+        response
+    };
+    guild_settings.insert(guild, settings);
+    settings::save(&state.config.storage_path, &guild_settings)?;
+    text(response)
+}
+
+fn entrants(_: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let race = state.race.read();
+    let response = match *race {
+        Race::NoRace => return Err(Error::NoRace),
+        Race::Setup(ref setup) |
+        Race::Countdown(ref setup) => {
+            let mut message = setup.title().map_or_else(String::new, |t| format!("{}\n", t));
+            if let Some(start_at) = setup.start_at {
+                write!(message, "Starts at {}\n", history::format_date_time(start_at)).unwrap();
+            }
+            for (index, &(entrant, status)) in setup.entrants.iter().enumerate() {
+                if index > 0 {
+                    message.push_str(", ");
+                } else {
+                    message.push_str("Entrants: ");
+                }
+                write!(message, "{}", name(state, entrant)?).unwrap();
+                if let Some(team) = setup.teams.get(&entrant) {
+                    write!(message, " [{}]", team).unwrap();
+                }
+                write!(message, " ({})", if status { "Ready" } else { "Not Ready" }).unwrap();
+            }
+            message
+        }
+        Race::InProgress(ref race) => {
+            let mut message = format!("{} - {}\n", race.game, race.category);
+            if let Some(ref goal) = race.goal {
+                write!(message, "Goal: {}\n", goal).unwrap();
+            }
+            for (index, entrant) in race.entrants.iter().enumerate() {
+                if index > 0 {
+                    message.push_str(", ");
+                } else {
+                    message.push_str("Entrants: ");
+                }
+                write!(message, "{}", name(state, entrant.id)?).unwrap();
+                if let Some(ref team) = entrant.team {
+                    write!(message, " [{}]", team).unwrap();
+                }
+                if let Some(time) = entrant.finish_time {
+                    write!(message, " (Finished {})", format_time(time)).unwrap();
+                } else if entrant.forfeited {
+                    message.push_str(" (Forfeited)");
+                }
+            }
+            message
+        }
+    };
+    text(response)
+}
+
+fn enter(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    // `!enter team <name>` joins a team in a team race.
+    let team = match params.get(0) {
+        Some(keyword) if keyword.eq_ignore_ascii_case("team") && params.len() > 1 => {
+            Some(params[1..].join(" "))
+        }
+        Some(_) => return Err(Error::Usage("!enter team <name>")),
+        None => None,
+    };
+
+    let mut race = state.race.write();
+    match *race {
+        Race::NoRace => Err(Error::NoRace),
+        Race::Setup(ref mut setup) => {
+            if setup.entrants.iter().any(|&(id, _)| id == invoker.id) {
+                Err(Error::AlreadyEntered)
+            } else if setup.closed {
+                Err(Error::EntryClosed)
+            } else if setup.team_mode.is_some() && team.is_none() {
+                Err(Error::Usage("!enter team <name>"))
+            } else if setup.team_mode.is_none() && team.is_some() {
+                Err(Error::NotTeamRace)
+            } else {
+                user(state, invoker); // Make sure the user exists
+                setup.entrants.push((invoker.id, false));
+                if let Some(team) = team {
+                    // Teammates share the spelling of whoever joined first.
+                    let team = setup
+                        .teams
+                        .values()
+                        .find(|t| t.eq_ignore_ascii_case(&team))
+                        .cloned()
+                        .unwrap_or(team);
+                    let response = format!("You successfully entered the race for {}!", team);
+                    setup.teams.insert(invoker.id, team);
+                    return text(response);
+                }
+                text("You successfully entered the race!")
+            }
+        }
+        Race::Countdown(_) |
+        Race::InProgress(_) => Err(Error::RaceInProgress),
+    }
+}
+
+fn ready(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    let mut replies = Vec::new();
+    match *race {
+        Race::NoRace => return Err(Error::NoRace),
+        Race::Countdown(_) |
+        Race::InProgress(_) => return Err(Error::RaceInProgress),
+        Race::Setup(ref mut setup) => {
+            if let Some(&mut (_, ref mut status)) =
+                setup
+                    .entrants
+                    .iter_mut()
+                    .filter(|&&mut (id, _)| id == invoker.id)
+                    .next() {
+                if *status {
+                    return Err(Error::AlreadyReady);
+                }
+                *status = true;
+            } else {
+                return Err(Error::NotEntered);
+            }
+            replies.push(Reply::Text(String::from("You are now ready for the race!")));
+        }
+    }
+
+    countdown_if_ready(invoker, state, &mut race, &mut replies);
+    Ok(replies)
+}
+
+fn watch(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    let watchers = race.watchers_mut().ok_or(Error::NoRace)?;
+    if watchers.contains(&invoker.id) {
+        return Err(Error::AlreadyWatching);
+    }
+    watchers.push(invoker.id);
+    text("You are now watching the race. You'll get a message when it starts, when a runner \
+          gets a gold or finishes and once the results are in.")
+}
+
+fn unwatch(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    let watchers = race.watchers_mut().ok_or(Error::NoRace)?;
+    if !watchers.contains(&invoker.id) {
+        return Err(Error::NotWatching);
+    }
+    watchers.retain(|&id| id != invoker.id);
+    text("You are no longer watching the race.")
+}
+
+fn unready(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    match *race {
+        Race::NoRace => Err(Error::NoRace),
+        Race::Countdown(_) |
+        Race::InProgress(_) => Err(Error::RaceInProgress),
+        Race::Setup(ref mut setup) => {
+            match setup.entrants.iter_mut().find(|&&mut (id, _)| id == invoker.id) {
+                Some(&mut (_, ref mut status)) => {
+                    if !*status {
+                        return Err(Error::NotReady);
+                    }
+                    *status = false;
+                    text("You are no longer ready for the race.")
+                }
+                None => Err(Error::NotEntered),
+            }
+        }
+    }
+}
+
+/// Leaves the race while it is being set up, or forfeits it once it is in
+/// progress.
+fn leave(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    let mut replies = Vec::new();
+    match *race {
+        Race::NoRace => return Err(Error::NoRace),
+        Race::Countdown(_) => return Err(Error::RaceInProgress),
+        Race::Setup(ref mut setup) => {
+            if !setup.entrants.iter().any(|&(id, _)| id == invoker.id) {
+                return Err(Error::NotEntered);
+            }
+            setup.entrants.retain(|&(id, _)| id != invoker.id);
+            replies.push(Reply::Text(String::from("You left the race.")));
+        }
+        Race::InProgress(ref mut in_progress) => {
+            if !in_progress.entrants.iter().any(|e| e.id == invoker.id) {
+                return Err(Error::NotEntered);
+            }
+            if !in_progress.forfeit(invoker.id) {
+                return Err(Error::NotRunning);
+            }
+            replies.push(Reply::Text(format!("{} forfeited the race.", invoker.name)));
+        }
+    }
+
+    let abandoned = match *race {
+        Race::Setup(ref setup) => setup.entrants.is_empty(),
+        _ => false,
+    };
+    if abandoned {
+        *race = Race::NoRace;
+        replies.push(Reply::Text(String::from("Everyone left, so the race got cancelled.")));
+    }
+
+    countdown_if_ready(invoker, state, &mut race, &mut replies);
+    update_race(state, &mut race, &mut replies)?;
+    Ok(replies)
+}
+
+/// Cancels the race if it got stuck in setup or in progress for longer than
+/// configured. Returns the channel the race was created in, along with the
+/// announcement.
+pub fn expire_race(state: &LSState) -> Option<(u64, String)> {
+    let timed_out = |since: Instant, minutes: u64| {
+        minutes > 0 && since.elapsed() > Duration::from_secs(minutes * 60)
+    };
+    let mut race = state.race.write();
+    let channel = match *race {
+        Race::Setup(ref setup) if setup.start_at.is_none() &&
+                                  timed_out(setup.created, state.config.setup_timeout) => {
+            setup.channel
+        }
+        // The frontend may never get to start the race once it counted down.
+        Race::Countdown(ref setup) if timed_out(setup.created, state.config.setup_timeout) => {
+            setup.channel
+        }
+        Race::InProgress(ref in_progress) if timed_out(in_progress.start,
+                                                       state.config.race_timeout) => {
+            in_progress.channel
+        }
+        _ => return None,
+    };
+
+    *race = Race::NoRace;
+    Some((channel, String::from("The race timed out and got cancelled.")))
+}
+
+/// The race's creator, on whose behalf replies to nobody in particular are
+/// posted in the race's channel.
+fn creator_invoker(state: &LSState, setup: &RaceSetup) -> Invoker {
+    Invoker {
+        id: setup.creator,
+        name: name(state, setup.creator).unwrap_or_default(),
+        channel: setup.channel,
+        guild: setup.guild,
+        manage_guild: false,
+        roles: Vec::new(),
+    }
+}
+
+/// Reminds the entrants of a scheduled race shortly before it starts, both in
+/// the race's channel and by direct message. Returns the replies on behalf of
+/// the race's creator.
+pub fn remind_entrants(state: &LSState) -> Option<(Invoker, Vec<Reply>)> {
+    let mut race = state.race.write();
+    let setup = match *race {
+        Race::Setup(ref mut setup) => setup,
+        _ => return None,
+    };
+    let now = history::now();
+    let start_at = match setup.start_at {
+        Some(start_at) if start_at <= now + state.config.reminder * 60 => start_at,
+        _ => return None,
+    };
+    if setup.reminded || state.config.reminder == 0 {
+        return None;
+    }
+    setup.reminded = true;
+
+    let entrants = setup
+        .entrants
+        .iter()
+        .filter_map(|&(id, _)| name(state, id).ok())
+        .collect::<Vec<_>>();
+    let reminder = format!("Reminder: Race #{} starts in {} minutes!",
+                           setup.id,
+                           (start_at.saturating_sub(now) + 59) / 60);
+    let mut replies = vec![Reply::Text(format!("{} Entrants: {}", reminder, entrants.join(", ")))];
+    for &(id, _) in &setup.entrants {
+        replies.push(Reply::Direct(id, reminder.clone()));
+    }
+    Some((creator_invoker(state, setup), replies))
+}
+
+/// Counts down a scheduled race that is due, if everyone is ready. Otherwise
+/// the race waits for its entrants like any other race. Returns the replies
+/// on behalf of the race's creator.
+pub fn start_scheduled_race(state: &LSState) -> Option<(Invoker, Vec<Reply>)> {
+    let mut race = state.race.write();
+    let (invoker, waiting) = match *race {
+        Race::Setup(ref mut setup) => {
+            match setup.start_at {
+                Some(start_at) if start_at <= history::now() => {}
+                _ => return None,
+            }
+            setup.start_at = None;
+            // The setup timeout applies from the scheduled start on.
+            setup.created = Instant::now();
+
+            let invoker = creator_invoker(state, setup);
+            let waiting = setup
+                .entrants
+                .iter()
+                .filter(|&&(_, ready)| !ready)
+                .filter_map(|&(id, _)| name(state, id).ok())
+                .collect::<Vec<_>>();
+            (invoker, waiting)
+        }
+        _ => return None,
+    };
+
+    let mut replies = Vec::new();
+    countdown_if_ready(&invoker, state, &mut race, &mut replies);
+    if replies.is_empty() {
+        replies.push(Reply::Text(format!("It's time for the race! Waiting for {} to get ready.",
+                                         waiting.join(", "))));
+    }
+    Some((invoker, replies))
+}
+
+/// Posts replies that weren't asked for by a command through the
+/// announcers, counting down like the frontends do.
+fn announce_replies(state: &LSState, invoker: &Invoker, replies: Vec<Reply>) {
+    for reply in countdowns_last(replies) {
+        match reply {
+            Reply::Text(text) |
+            Reply::File { text, .. } => state.announce(invoker.channel, &text),
+            Reply::Embed(embed) => state.announce(invoker.channel, &embed.to_text()),
+            // Posting the standings on every split would flood the channel,
+            // the results are posted once the race is over anyway.
+            Reply::RaceStatus { .. } => {}
+            Reply::Direct(user, text) => state.announce_direct(user, &text),
+            Reply::Countdown(seconds) => {
+                state.announce(invoker.channel,
+                               &format!("All entrants are ready! Starting in {} seconds.",
+                                        seconds));
+                thread::sleep(Duration::from_secs(seconds));
+                state.announce(invoker.channel, "Go!");
+                match start_race(invoker, state) {
+                    Ok(replies) => announce_replies(state, invoker, replies),
+                    Err(e) => error!("Couldn't start the scheduled race: {}", e),
+                }
+            }
+            // Async runs are only ever started by a command.
+            Reply::AsyncCountdown { race, .. } => {
+                warn!("Can't count down async race {} here", race)
+            }
+        }
+    }
+}
+
+/// Periodically cancels races that timed out, reminds the entrants of
+/// scheduled races and starts them, and closes async races. Everything is
+/// announced in the channel the race was created in.
+pub fn watch_races(state: Arc<LSState>) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+                      thread::sleep(Duration::from_secs(TIMEOUT_INTERVAL));
+                      if let Some((channel, text)) = expire_race(&state) {
+                          info!("{}", text);
+                          state.announce(channel, &text);
+                      }
+                      if let Some((invoker, replies)) = remind_entrants(&state) {
+                          announce_replies(&state, &invoker, replies);
+                      }
+                      if let Some((invoker, replies)) = start_scheduled_race(&state) {
+                          announce_replies(&state, &invoker, replies);
+                      }
+                      for (channel, text) in close_async_races(&state) {
+                          state.announce(channel, &text);
+                      }
+                  })
+}
+
+/// Starts the countdown once every entrant is ready.
+fn countdown_if_ready(invoker: &Invoker,
+                      state: &LSState,
+                      race: &mut Race,
+                      replies: &mut Vec<Reply>) {
+    let all_ready = match *race {
+        Race::Setup(ref setup) => {
+            setup.start_at.is_none() && !setup.entrants.is_empty() &&
+            setup.entrants.iter().all(|&(_, status)| status)
+        }
+        _ => false,
+    };
+    if all_ready {
+        start_countdown(invoker, state, race, replies);
+    }
+}
+
+fn start_countdown(invoker: &Invoker,
+                   state: &LSState,
+                   race: &mut Race,
+                   replies: &mut Vec<Reply>) {
+    match mem::replace(race, Race::NoRace) {
+        Race::Setup(mut setup) => {
+            replies.push(Reply::Countdown(state.countdown(invoker.guild)));
+            // The setup timeout applies from the countdown on.
+            setup.created = Instant::now();
+            // The entrants may not be looking at the channel, so they get
+            // a heads-up, which the frontend sends before counting down.
+            for &(entrant, _) in &setup.entrants {
+                replies.push(Reply::Direct(entrant,
+                                           format!("The countdown for race #{} started, get \
+                                                    ready!",
+                                                   setup.id)));
+            }
+            *race = Race::Countdown(setup);
+        }
+        other => *race = other,
+    }
+}
+
+/// Starts the race once the frontend finished counting down.
+pub fn start_race(invoker: &Invoker, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    let setup = match mem::replace(&mut *race, Race::NoRace) {
+        Race::Countdown(setup) => setup,
+        other => {
+            *race = other;
+            return Ok(Vec::new());
+        }
+    };
+
+    // Unless the creator said otherwise, the race is recorded as the game
+    // and category the creator runs.
+    let title = state
+        .users
+        .get_mut(&setup.creator)
+        .ok_or(Error::UserNotFound(setup.creator))?
+        .eval_layout()
+        .title;
+    let game = setup.game.clone().unwrap_or(title.game);
+    let category = setup.category.clone().unwrap_or(title.category);
+
+    // The board is only generated after the countdown, so nobody gets to see
+    // it early.
+    let bingo = match setup.bingo {
+        Some(ref variant) => {
+            let seed = thread_rng().gen_range(0, 1_000_000);
+            let players = setup.entrants.iter().map(|&(id, _)| id).collect();
+            Some(BingoGame::new(variant.clone(), seed, players)?)
+        }
+        None => None,
+    };
+
+    let in_progress = RaceInProgress::new(&setup, game, category, bingo);
+
+    // Relay runners start once their teammate hands off to them.
+    for entrant in in_progress.entrants.iter().filter(|e| !e.waiting) {
+        let mut user = state
+            .users
+            .get_mut(&entrant.id)
+            .ok_or(Error::UserNotFound(entrant.id))?;
+        user.timer.split();
+    }
+
+    let mut replies = Vec::new();
+    if let Some(ref game) = in_progress.bingo {
+        let options = state.bingo_style(invoker.guild, game.variant.template);
+        replies.push(Reply::File {
+                         name: String::from("bingo.png"),
+                         content: game.render_png(&options)?,
+                         text: game.variant.url(&state.config.public_url, game.seed),
+                     });
+    }
+
+    replies.push(Reply::RaceStatus {
+                     race: in_progress.id,
+                     embed: race_status(state, &in_progress)?,
+                     finished: false,
+                 });
+    notify_watchers(&in_progress,
+                    format!("Race #{} started!", in_progress.id),
+                    &mut replies);
+    *race = Race::InProgress(in_progress);
+    Ok(replies)
+}
+
+fn record_race(state: &LSState, race: &RaceInProgress) -> Result<RaceRecord, Error> {
+    let mut entrants = Vec::new();
+    for (index, (id, time)) in race.results().into_iter().enumerate() {
+        entrants.push(EntrantRecord {
+                          id: id,
+                          name: name(state, id)?,
+                          place: Some(index + 1),
+                          time: Some(time),
+                          forfeited: false,
+                          team: None,
+                          splits: Vec::new(),
+                      });
+    }
+    for entrant in race.entrants.iter().filter(|e| e.finish_time.is_none()) {
+        entrants.push(EntrantRecord {
+                          id: entrant.id,
+                          name: name(state, entrant.id)?,
+                          place: None,
+                          time: None,
+                          forfeited: entrant.forfeited,
+                          team: None,
+                          splits: Vec::new(),
+                      });
+    }
+
+    // The entrants of a team race share their team's placement.
+    let mut teams = race.teams()
+        .into_iter()
+        .map(|team| {
+                 TeamRecord {
+                     name: team.to_owned(),
+                     place: None,
+                     time: race.team_time(team),
+                 }
+             })
+        .collect::<Vec<_>>();
+    teams.sort_by_key(|team| (team.time.is_none(), team.time));
+    for (index, team) in teams.iter_mut().enumerate() {
+        if team.time.is_some() {
+            team.place = Some(index + 1);
+        }
+    }
+    for record in &mut entrants {
+        if let Some(entrant) = race.entrants.iter().find(|e| e.id == record.id) {
+            record.team = entrant.team.clone();
+            // Relay runners' splits count from when they took over.
+            record.splits = entrant.splits.iter().map(|&split| split - entrant.start).collect();
+        }
+        if let Some(ref team) = record.team {
+            record.place = teams.iter().find(|t| &t.name == team).and_then(|t| t.place);
+        }
+    }
+    entrants.sort_by_key(|e| (e.place.is_none(), e.place));
+
+    let bingo = race.bingo
+        .as_ref()
+        .map(|game| {
+                 format!("{}{} bingo ({}) {}",
+                         if game.variant.lockout { "Lockout " } else { "" },
+                         game.variant.template,
+                         game.variant.mode,
+                         game.variant.url(&state.config.public_url, game.seed))
+             });
+
+    Ok(RaceRecord {
+           id: race.id,
+           game: race.game.clone(),
+           category: race.category.clone(),
+           goal: race.goal.clone().or(bingo),
+           date: history::date(race.start.elapsed()),
+           entrants: entrants,
+           teams: teams,
+       })
+}
+
+/// Lists the entrants by how far they got, with their deltas to the leader.
+fn race_status(state: &LSState, race: &RaceInProgress) -> Result<Embed, Error> {
+    let mut entrants = race.entrants.iter().collect::<Vec<_>>();
+    entrants.sort_by(|a, b| {
+        let rank = |e: &Entrant| (e.forfeited, e.finish_time.is_none());
+        rank(a)
+            .cmp(&rank(b))
+            .then_with(|| a.finish_time.cmp(&b.finish_time))
+            .then_with(|| b.splits.len().cmp(&a.splits.len()))
+            .then_with(|| a.splits.last().cmp(&b.splits.last()))
+    });
+
+    let mut fields = Vec::new();
+    for (index, entrant) in entrants.into_iter().enumerate() {
+        let status = if let Some(time) = entrant.finish_time {
+            format!("Finished in {}", format_time(time))
+        } else if entrant.forfeited {
+            String::from("Forfeited")
+        } else if entrant.waiting {
+            String::from("Waiting for the handoff")
+        } else {
+            let split = state
+                .users
+                .get(&entrant.id)
+                .ok_or(Error::UserNotFound(entrant.id))?
+                .timer
+                .current_split()
+                .map_or_else(String::new, |s| format!(" ({})", s.name()));
+            let delta = match race.delta(entrant) {
+                Some((true, delta)) => format!(" -{}", format_time(delta)),
+                Some((false, delta)) => format!(" +{}", format_time(delta)),
+                None => String::new(),
+            };
+            format!("Split {}{}{}", entrant.splits.len() + 1, split, delta)
+        };
+        let mut label = format!("{}. {}", index + 1, name(state, entrant.id)?);
+        if let Some(ref team) = entrant.team {
+            write!(label, " [{}]", team).unwrap();
+        }
+        fields.push((label, status));
+    }
+
+    let mut description = race.goal
+        .as_ref()
+        .map_or_else(String::new, |goal| format!("Goal: {}\n", goal));
+    if race.is_finished() {
+        description.push_str("The race is over!");
+    } else {
+        write!(description, "Running for {}", format_time(race.start.elapsed())).unwrap();
+    }
+
+    Ok(Embed {
+           title: format!("Race #{}: {} - {}", race.id, race.game, race.category),
+           description: description,
+           colour: (0x4d, 0xa6, 0xff),
+           fields: fields,
+       })
+}
+
+fn finish(state: &LSState,
+          race: &mut Race,
+          id: u64,
+          replies: &mut Vec<Reply>)
+          -> Result<(), Error> {
+    if let Race::InProgress(ref mut in_progress) = *race {
+        let (time, place) = in_progress.finish(id).ok_or(Error::AlreadyFinished)?;
+        let text = format!("{} finished the race in **{}** (#{})!",
+                           name(state, id)?,
+                           format_time(time),
+                           place);
+        replies.push(Reply::Text(text.clone()));
+        notify_watchers(in_progress, format!("Race #{}: {}", in_progress.id, text), replies);
+
+        if let Some(next) = in_progress.hand_off(id) {
+            state
+                .users
+                .get_mut(&next)
+                .ok_or(Error::UserNotFound(next))?
+                .timer
+                .split();
+            replies.push(Reply::Text(format!("{} takes over, go!", name(state, next)?)));
+        }
+    }
+    update_race(state, race, replies)
+}
+
+/// Posts the race's status, as well as the results once it is over, which
+/// ends the race.
+fn update_race(state: &LSState, race: &mut Race, replies: &mut Vec<Reply>) -> Result<(), Error> {
+    if let Race::InProgress(ref in_progress) = *race {
+        replies.push(Reply::RaceStatus {
+                         race: in_progress.id,
+                         embed: race_status(state, in_progress)?,
+                         finished: in_progress.is_finished(),
+                     });
+        if !in_progress.is_finished() {
+            return Ok(());
+        }
+    } else {
+        return Ok(());
+    }
+
+    // The race is over even if recording it fails, so it never gets
+    // recorded twice.
+    let in_progress = match mem::replace(race, Race::NoRace) {
+        Race::InProgress(in_progress) => in_progress,
+        _ => return Ok(()),
+    };
+    let record = record_race(state, &in_progress)?;
+    let mut results = format!("The race is over! Results of {} - {}:\n",
+                              record.game,
+                              record.category);
+    if let Some(ref goal) = in_progress.goal {
+        write!(results, "Goal: {}\n", goal).unwrap();
+    }
+    results.push_str(&record.results());
+    replies.push(Reply::Text(results.clone()));
+    notify_watchers(&in_progress, format!("Race #{}: {}", record.id, results), replies);
+
+    store_race(state, record);
+    Ok(())
+}
+
+/// Adds the finished race to the history and updates the ratings with it.
+/// The race counts even if the history can't be saved, which only gets
+/// logged.
+fn store_race(state: &LSState, record: RaceRecord) {
+    state.ratings.write().update(&record);
+    let mut races = state.history.write();
+    races.push(record);
+    if let Err(e) = history::save(&state.config.storage_path, &races) {
+        error!("Couldn't save the race history: {}", e);
+    }
+}
+
+fn mark(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let id = invoker.id;
+    {
+        let mut races = state.async_races.write();
+        if let Some(async_race) = races
+               .iter_mut()
+               .find(|r| r.race.entrants.iter().any(|e| e.id == id && e.is_running())) {
+            return mark_async(invoker, &params, async_race);
+        }
+    }
+
+    let mut race = state.race.write();
+    let mut replies = Vec::new();
+    let has_won = match *race {
+        Race::InProgress(ref mut in_progress) => {
+            if !in_progress
+                    .entrants
+                    .iter()
+                    .any(|e| e.id == id && e.is_running()) {
+                return Err(Error::NotRunning);
+            }
+            let game = match in_progress.bingo {
+                Some(ref mut game) => game,
+                None => return Err(Error::NotBingoRace),
+            };
+            let cell = game.find_cell(&params).ok_or(Error::GoalNotFound)?;
+            match game.mark(id, cell) {
+                Mark::Marked => {}
+                Mark::AlreadyMarked => {
+                    return Err(Error::AlreadyMarked(game.goal(cell).to_owned()));
+                }
+                Mark::ClaimedBy(owner) => {
+                    return Err(Error::AlreadyClaimed {
+                                   goal: game.goal(cell).to_owned(),
+                                   owner: name(state, owner)?,
+                               });
+                }
+            }
+
+            if game.variant.lockout {
+                let options = state.bingo_style(invoker.guild, game.variant.template);
+                let color = game.color(id).map_or("", |(color, _)| color);
+                replies.push(Reply::File {
+                                 name: String::from("bingo.png"),
+                                 content: game.render_png(&options)?,
+                                 text: format!("{} ({}) claimed **{}** and holds {} of 25 goals.",
+                                               name(state, id)?,
+                                               color,
+                                               game.goal(cell),
+                                               game.marked_cells(id)),
+                             });
+            } else {
+                replies.push(Reply::Text(format!("Marked **{}**.", game.goal(cell))));
+            }
+            game.has_won(id)
+        }
+        Race::NoRace => return Err(Error::NoRace),
+        Race::Setup(_) |
+        Race::Countdown(_) => return Err(Error::RaceNotStarted),
+    };
+
+    if has_won {
+        finish(state, &mut race, id, &mut replies)?;
+    }
+    Ok(replies)
+}
+
+fn done(invoker: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let mut race = state.race.write();
+    match *race {
+        Race::NoRace => return Err(Error::NoRace),
+        Race::Setup(_) |
+        Race::Countdown(_) => return Err(Error::RaceNotStarted),
+        // Bingo races are finished by marking a line, which `mark` detects.
+        Race::InProgress(ref in_progress) if in_progress.bingo.is_some() => {
+            return Err(Error::BingoRace)
+        }
+        Race::InProgress(ref in_progress) => {
+            match in_progress.entrants.iter().find(|e| e.id == invoker.id) {
+                None => return Err(Error::NotEntered),
+                Some(entrant) if entrant.forfeited || entrant.waiting => {
+                    return Err(Error::NotRunning)
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    let mut replies = Vec::new();
+    finish(state, &mut race, invoker.id, &mut replies)?;
+    Ok(replies)
+}
+
+/// Opens an async race until the given time. Everyone starts their own run
+/// whenever they like within that time, on the same goal or bingo board.
+fn create_async(invoker: &Invoker, mut params: Vec<String>, state: &LSState) -> CommandResult {
+    const USAGE: &'static str = "!create-async <1h30m | HH:MM> [<game> | <category> | <goal>]";
+    let closes_at = if params.is_empty() {
+        None
+    } else {
+        race::parse_start_time(&params.remove(0), history::now())
+    };
+    let closes_at = closes_at.ok_or(Error::Usage(USAGE))?;
+    let (bingo, mut parts) = parse_race_params(invoker, &params, state)?;
+
+    // Unless the creator said otherwise, the race is recorded as the game
+    // and category the creator runs.
+    let title = user(state, invoker).eval_layout().title;
+    let setup = RaceSetup {
+        id: state.next_race_id(),
+        creator: invoker.id,
+        entrants: Vec::new(),
+        team_mode: None,
+        teams: HashMap::new(),
+        game: parts[0].take(),
+        category: parts[1].take(),
+        goal: parts[2].take(),
+        bingo: None,
+        closed: false,
+        channel: invoker.channel,
+        guild: invoker.guild,
+        created: Instant::now(),
+        start_at: None,
+        reminded: false,
+        watchers: Vec::new(),
+    };
+    let game = setup.game.clone().unwrap_or(title.game);
+    let category = setup.category.clone().unwrap_or(title.category);
+
+    // The seed is picked right away, so everyone gets the same board. It is
+    // only revealed to each entrant once their run starts.
+    let bingo = match bingo {
+        Some(mut variant) => {
+            // Nobody can lock out a goal when everyone runs on their own.
+            variant.lockout = false;
+            let seed = thread_rng().gen_range(0, 1_000_000);
+            Some(BingoGame::new(variant, seed, Vec::new())?)
+        }
+        None => None,
+    };
+
+    let async_race = AsyncRace {
+        race: RaceInProgress::new(&setup, game, category, bingo),
+        closes_at: closes_at,
+    };
+    let mut response = format!("Created async {} #{}: {} - {}",
+                               if async_race.race.bingo.is_some() {
+                                   "bingo race"
+                               } else {
+                                   "race"
+                               },
+                               async_race.race.id,
+                               async_race.race.game,
+                               async_race.race.category);
+    if let Some(ref goal) = async_race.race.goal {
+        write!(response, ": {}", goal).unwrap();
+    }
+    write!(response,
+           "\nStart your run with `{}async-start {}` before it closes at {}.",
+           state.prefix(invoker.guild),
+           async_race.race.id,
+           history::format_date_time(closes_at))
+            .unwrap();
+
+    state.async_races.write().push(async_race);
+    text(response)
+}
+
+/// Finds the open async race by the ID in the parameters, which can be left
+/// out while there is only one.
+fn find_async_race<'a>(races: &'a mut Vec<AsyncRace>,
+                       params: &[String],
+                       usage: &'static str)
+                       -> Result<&'a mut AsyncRace, Error> {
+    match parse_race_id(params) {
+        Some(id) => {
+            races
+                .iter_mut()
+                .find(|r| r.race.id == id)
+                .ok_or(Error::RaceNotFound(id))
+        }
+        None if races.is_empty() => Err(Error::NoRace),
+        None if races.len() == 1 => Ok(&mut races[0]),
+        None => Err(Error::Usage(usage)),
+    }
+}
+
+/// Enters the user into the async race and counts down their run.
+fn async_start(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let mut races = state.async_races.write();
+    let async_race = find_async_race(&mut races, &params, "!async-start <race>")?;
+    if async_race.closes_at <= history::now() {
+        return Err(Error::EntryClosed);
+    }
+    user(state, invoker); // Make sure the user exists
+    if !async_race.race.enter(invoker.id) {
+        return Err(Error::AlreadyEntered);
+    }
+    Ok(vec![Reply::AsyncCountdown {
+                race: async_race.race.id,
+                seconds: state.countdown(invoker.guild),
+            }])
+}
+
+/// Starts the user's run of the async race once the frontend finished
+/// counting down.
+pub fn start_async_run(invoker: &Invoker, id: u64, state: &LSState) -> CommandResult {
+    let mut races = state.async_races.write();
+    let async_race = races
+        .iter_mut()
+        .find(|r| r.race.id == id)
+        .ok_or(Error::RaceNotFound(id))?;
+    if !async_race.race.start_run(invoker.id) {
+        return Ok(Vec::new());
+    }
+    state
+        .users
+        .get_mut(&invoker.id)
+        .ok_or(Error::UserNotFound(invoker.id))?
+        .timer
+        .split();
+
+    let mut replies = vec![Reply::Text(format!("{} started their run of async race #{}!",
+                                               invoker.name,
+                                               id))];
+    if let Some(ref game) = async_race.race.bingo {
+        replies.push(Reply::Direct(invoker.id,
+                                   format!("Your board for async race #{}: {}",
+                                           id,
+                                           game.variant.url(&state.config.public_url,
+                                                            game.seed))));
+    }
+    Ok(replies)
+}
+
+/// Finishes the user's run of the async race. Their time is kept secret until
+/// the race closes.
+fn async_done(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let mut races = state.async_races.write();
+    let async_race = find_async_race(&mut races, &params, "!async-done <race>")?;
+    if async_race.race.bingo.is_some() {
+        return Err(Error::BingoRace);
+    }
+    match async_race.race.entrants.iter().find(|e| e.id == invoker.id) {
+        None => return Err(Error::NotEntered),
+        Some(entrant) if entrant.forfeited || entrant.waiting => return Err(Error::NotRunning),
+        Some(_) => {}
+    }
+    finish_async_run(invoker, async_race)
+}
+
+fn finish_async_run(invoker: &Invoker, async_race: &mut AsyncRace) -> CommandResult {
+    let (time, _) = async_race
+        .race
+        .finish(invoker.id)
+        .ok_or(Error::AlreadyFinished)?;
+
+    let id = async_race.race.id;
+    Ok(vec![Reply::Direct(invoker.id,
+                          format!("You finished async race #{} in **{}**! The results are \
+                                   revealed once it closes at {}.",
+                                  id,
+                                  format_time(time),
+                                  history::format_date_time(async_race.closes_at))),
+            Reply::Text(format!("{} finished their run of async race #{}.", invoker.name, id))])
+}
+
+/// Marks a goal on the board of the async race the user is running. Getting
+/// bingo finishes their run.
+fn mark_async(invoker: &Invoker, params: &[String], async_race: &mut AsyncRace) -> CommandResult {
+    let (goal, has_won) = {
+        let game = match async_race.race.bingo {
+            Some(ref mut game) => game,
+            None => return Err(Error::NotBingoRace),
+        };
+        let cell = game.find_cell(params).ok_or(Error::GoalNotFound)?;
+        match game.mark(invoker.id, cell) {
+            Mark::Marked => {}
+            // Async races are never lockout, so nobody else can claim a goal.
+            Mark::AlreadyMarked |
+            Mark::ClaimedBy(_) => return Err(Error::AlreadyMarked(game.goal(cell).to_owned())),
+        }
+        (game.goal(cell).to_owned(), game.has_won(invoker.id))
+    };
+
+    let mut replies = vec![Reply::Text(format!("Marked **{}**.", goal))];
+    if has_won {
+        replies.extend(finish_async_run(invoker, async_race)?);
+    }
+    Ok(replies)
+}
+
+fn async_forfeit(invoker: &Invoker, params: Vec<String>, state: &LSState) -> CommandResult {
+    let mut races = state.async_races.write();
+    let async_race = find_async_race(&mut races, &params, "!async-forfeit <race>")?;
+    if !async_race.race.entrants.iter().any(|e| e.id == invoker.id) {
+        return Err(Error::NotEntered);
+    }
+    if !async_race.race.forfeit(invoker.id) {
+        return Err(Error::NotRunning);
+    }
+    text(format!("{} forfeited async race #{}.", invoker.name, async_race.race.id))
+}
+
+fn async_races(_: &Invoker, _: Vec<String>, state: &LSState) -> CommandResult {
+    let races = state
+        .async_races
+        .read()
+        .iter()
+        .map(|r| {
+                 format!("#{} {} - {} ({} entrants), closes at {}",
+                         r.race.id,
+                         r.race.game,
+                         r.race.category,
+                         r.race.entrants.len(),
+                         history::format_date_time(r.closes_at))
+             })
+        .collect::<Vec<_>>();
+    if races.is_empty() {
+        text("There are no open async races.")
+    } else {
+        text(format!("Open async races:\n{}", races.join("\n")))
+    }
+}
+
+/// Closes the async races that ran out of time and reveals their results.
+/// Entrants that didn't finish by then forfeit. Returns the channel each
+/// race was created in, along with the announcement.
+pub fn close_async_races(state: &LSState) -> Vec<(u64, String)> {
+    let now = history::now();
+    let closed = {
+        let mut races = state.async_races.write();
+        let (closed, open): (Vec<AsyncRace>, Vec<AsyncRace>) =
+            mem::replace(&mut *races, Vec::new())
+                .into_iter()
+                .partition(|r| r.closes_at <= now);
+        *races = open;
+        closed
+    };
+
+    let mut announcements = Vec::new();
+    for mut async_race in closed {
+        let race = &mut async_race.race;
+        if race.entrants.is_empty() {
+            announcements.push((race.channel,
+                                format!("Async race #{} closed without any entrants.", race.id)));
+            continue;
+        }
+        let unfinished = race.entrants
+            .iter()
+            .filter(|e| e.finish_time.is_none())
+            .map(|e| e.id)
+            .collect::<Vec<_>>();
+        for id in unfinished {
+            race.forfeit(id);
+        }
+
+        let record = match record_race(state, race) {
+            Ok(record) => record,
+            Err(e) => {
+                error!("Couldn't record async race {}: {}", race.id, e);
+                continue;
+            }
+        };
+        let mut results = format!("Async race #{} is closed! Results of {} - {}:\n",
+                                  record.id,
+                                  record.game,
+                                  record.category);
+        if let Some(ref goal) = record.goal {
+            write!(results, "Goal: {}\n", goal).unwrap();
+        }
+        results.push_str(&record.results());
+        store_race(state, record);
+        announcements.push((race.channel, results));
+    }
+    announcements
+}
+
+/// A user without any permissions, chatting in the channel all the tests use.
+#[cfg(test)]
+fn invoker(id: u64) -> Invoker {
+    Invoker {
+        id: id,
+        name: format!("Runner {}", id),
+        channel: 1,
+        guild: 1,
+        manage_guild: false,
+        roles: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+fn run(state: &LSState, id: u64, message: &str) -> CommandResult {
+    let (command, params) = parse("!", message).unwrap();
+    execute(state, &invoker(id), &command, params).unwrap()
+}
+
+/// A state that starts out without anything stored, in a directory of its
+/// own so that tests running in parallel don't share it.
+#[cfg(test)]
+fn test_state(name: &str) -> LSState {
+    let storage_path = format!("target/test-storage/{}", name);
+    let _ = ::std::fs::remove_dir_all(&storage_path);
+    LSState::new(Config {
+                     storage_path: storage_path,
+                     ..Config::default()
+                 })
+}
+
+#[cfg(test)]
+fn texts(replies: &[Reply]) -> Vec<&str> {
+    replies
+        .iter()
+        .filter_map(|reply| match *reply {
+                        Reply::Text(ref text) => Some(text.as_str()),
+                        _ => None,
+                    })
+        .collect()
+}
+
+#[cfg(test)]
+fn countdown(replies: &[Reply]) -> Option<u64> {
+    replies
+        .iter()
+        .filter_map(|reply| match *reply {
+                        Reply::Countdown(seconds) => Some(seconds),
+                        _ => None,
+                    })
+        .next()
+}
+
+#[test]
+fn parse_commands() {
+    assert_eq!(parse("!", "!create-race bingo  short"),
+               Some((String::from("create-race"),
+                     vec![String::from("bingo"), String::from("short")])));
+    assert_eq!(parse("!", "split"), None);
+    assert_eq!(parse("!", "!"), None);
+}
+
+#[test]
+fn timer_commands() {
+    let state = test_state("timer-commands");
+    let replies = run(&state, 1, "!split").unwrap();
+    match replies[0] {
+        Reply::Embed(ref embed) => assert_eq!(embed.title, "Wind Waker - Any%"),
+        _ => panic!("Expected the layout"),
+    }
+    assert_eq!(run(&state, 1, "!load-splits").err(),
+               Some(Error::Usage("!load-splits <splits.io ID>")));
+
+    let replies = run(&state, 1, "!server-token").unwrap();
+    match replies[1] {
+        Reply::Direct(1, ref text) => {
+            assert!(text.starts_with("Connect to the LiveSplit Server at `localhost:16834`"))
+        }
+        _ => panic!("Expected the token"),
+    }
+}
+
+#[test]
+fn race() {
+    let state = test_state("race");
+    assert_eq!(run(&state, 1, "!ready").err(), Some(Error::NoRace));
+    assert_eq!(texts(&run(&state, 1, "!create-race").unwrap()),
+               ["Created a new race!"]);
+    assert_eq!(texts(&run(&state, 2, "!enter").unwrap()),
+               ["You successfully entered the race!"]);
+    assert_eq!(run(&state, 2, "!enter").err(), Some(Error::AlreadyEntered));
+    assert_eq!(run(&state, 3, "!ready").err(), Some(Error::NotEntered));
+    assert_eq!(texts(&run(&state, 1, "!ready").unwrap()),
+               ["You are now ready for the race!"]);
+    assert_eq!(run(&state, 1, "!ready").err(), Some(Error::AlreadyReady));
+
+    let replies = run(&state, 2, "!ready").unwrap();
+    assert!(countdown(&replies).is_some());
+    assert_eq!(run(&state, 3, "!enter").err(), Some(Error::RaceInProgress));
+
+    assert!(texts(&start_race(&invoker(2), &state).unwrap()).is_empty());
+
+    let replies = run(&state, 2, "!split").unwrap();
+    assert!(match replies[1] {
+                Reply::RaceStatus { race: 1, ref embed, .. } => {
+                    embed.fields[0].0 == "1. Runner 2" &&
+                    embed.fields[0].1.starts_with("Split 2 (Second) +")
+                }
+                _ => false,
+            });
+    assert!(replies.iter().all(|reply| match *reply {
+                                   Reply::RaceStatus { finished, .. } => !finished,
+                                   _ => true,
+                               }));
+
+    assert_eq!(run(&state, 3, "!done").err(), Some(Error::NotEntered));
+    let replies = run(&state, 2, "!done").unwrap();
+    assert!(texts(&replies)[0].starts_with("Runner 2 finished the race in"));
+    assert_eq!(run(&state, 2, "!done").err(), Some(Error::AlreadyFinished));
+    let replies = run(&state, 1, "!done").unwrap();
+    assert!(texts(&replies)[1]
+                .starts_with("The race is over! Results of Wind Waker - Any%:\n1. Runner 2"));
+    assert!(replies.iter().any(|reply| match *reply {
+                                   Reply::RaceStatus { finished, .. } => finished,
+                                   _ => false,
+                               }));
+    assert_eq!(run(&state, 1, "!entrants").err(), Some(Error::NoRace));
+}
+
+#[test]
+fn bingo_race() {
+    let state = test_state("bingo-race");
+    run(&state, 1, "!create-race bingo short").unwrap();
+    assert_eq!(run(&state, 1, "!mark 1 1").err(), Some(Error::RaceNotStarted));
+    run(&state, 1, "!ready").unwrap();
+
+    let replies = start_race(&invoker(1), &state).unwrap();
+    assert!(match replies[0] {
+                Reply::File { ref text, .. } => text.starts_with("http://localhost:8000/botw/bingo/"),
+                _ => false,
+            });
+
+    for col in 1..5 {
+        let replies = run(&state, 1, &format!("!mark 2 {}", col)).unwrap();
+        assert!(texts(&replies)[0].starts_with("Marked"));
+    }
+    assert!(match run(&state, 1, "!mark 2 1") {
+                Err(Error::AlreadyMarked(_)) => true,
+                _ => false,
+            });
+    assert_eq!(run(&state, 1, "!mark 6 1").err(), Some(Error::GoalNotFound));
+    assert_eq!(run(&state, 1, "!done").err(), Some(Error::BingoRace));
+    assert_eq!(run(&state, 2, "!mark 3 1").err(), Some(Error::NotRunning));
+
+    let replies = run(&state, 1, "!mark 2 5").unwrap();
+    assert!(texts(&replies)[2].starts_with("The race is over!"));
+}
+
+#[test]
+fn link_identities() {
+    let state = test_state("link-identities");
+    assert_eq!(run(&state, 1, "!link").err(),
+               Some(Error::Usage("!link twitch <name>")));
+    assert_eq!(run(&state, 1, "!link myspace Runner").err(),
+               Some(Error::UnknownService(String::from("myspace"))));
+
+    let verification_code = |replies: Vec<Reply>| match replies[1] {
+        Reply::Direct(_, ref text) => text.split('`').nth(1).unwrap()[8..].to_owned(),
+        _ => panic!("Expected the verification code"),
+    };
+    let code = verification_code(run(&state, 1, "!link twitch Runner").unwrap());
+
+    let chat_user = state.identity(irc::TWITCH_SERVICE, "runner");
+    let other_user = state.identity(irc::TWITCH_SERVICE, "other");
+    assert_eq!(run(&state, other_user, &format!("!verify {}", code)).err(),
+               Some(Error::InvalidVerificationCode));
+    assert_eq!(texts(&run(&state, chat_user, &format!("!verify {}", code)).unwrap()),
+               ["Linked runner to the account of Runner 1."]);
+    assert_eq!(state.identity(irc::TWITCH_SERVICE, "Runner"), 1);
+    assert_eq!(run(&state, chat_user, &format!("!verify {}", code)).err(),
+               Some(Error::InvalidVerificationCode));
+
+    // Moving the identity to another account keeps the previous account.
+    let code = verification_code(run(&state, 2, "!link twitch Runner").unwrap());
+    run(&state, 1, &format!("!verify {}", code)).unwrap();
+    assert_eq!(state.identity(irc::TWITCH_SERVICE, "Runner"), 2);
+    assert!(state.users.get(&1).is_some());
+}
+
+#[test]
+fn guild_settings() {
+    let state = test_state("guild-settings");
+    assert_eq!(run(&state, 1, "!config set countdown 3").err(),
+               Some(Error::NotModerator));
+
+    let mut moderator = invoker(2);
+    moderator.manage_guild = true;
+    let config = |invoker: &Invoker, message: &str| {
+        let (command, params) = parse("!", message).unwrap();
+        execute(&state, invoker, &command, params).unwrap()
+    };
+    assert_eq!(texts(&config(&moderator, "!config set race-channels <#5>").unwrap()),
+               ["Changed race-channels to <#5>"]);
+    assert_eq!(texts(&config(&moderator, "!config set moderator-role Race Mods").unwrap()),
+               ["Changed moderator-role to Race Mods"]);
+    assert_eq!(config(&moderator, "!config set volume 11").err(),
+               Some(Error::UnknownSetting(String::from("volume"))));
+
+    moderator.manage_guild = false;
+    moderator.roles.push(String::from("race mods"));
+    assert_eq!(texts(&config(&moderator, "!config get countdown").unwrap()),
+               ["countdown: (default)"]);
+
+    assert_eq!(run(&state, 1, "!create-race").err(), Some(Error::RaceChannel));
+    assert!(run(&state, 1, "!split").is_ok());
+}
+
+#[test]
+fn organize_race() {
+    let state = test_state("organize-race");
+    assert_eq!(run(&state, 1, "!cancel-race").err(), Some(Error::NoRace));
+    run(&state, 1, "!create-race").unwrap();
+    run(&state, 2, "!enter").unwrap();
+    run(&state, 3, "!enter").unwrap();
+    run(&state, 3, "!ready").unwrap();
+
+    assert_eq!(run(&state, 2, "!close-entry").err(), Some(Error::NotOrganizer));
+    assert_eq!(texts(&run(&state, 1, "!close-entry").unwrap()),
+               ["Entry to the race is closed now."]);
+    assert_eq!(run(&state, 4, "!enter").err(), Some(Error::EntryClosed));
+
+    assert_eq!(run(&state, 1, "!kick Runner 5").err(),
+               Some(Error::EntrantNotFound(String::from("Runner 5"))));
+    assert_eq!(texts(&run(&state, 1, "!kick runner 2").unwrap()),
+               ["Runner 2 got removed from the race."]);
+    assert_eq!(run(&state, 2, "!ready").err(), Some(Error::NotEntered));
+
+    let replies = run(&state, 1, "!force-start").unwrap();
+    assert_eq!(countdown(&replies), Some(10));
+    // Moderators of other guilds have no say over the race.
+    let mut moderator = invoker(6);
+    moderator.guild = 2;
+    moderator.manage_guild = true;
+    let (command, params) = parse("!", "!cancel-race").unwrap();
+    assert_eq!(execute(&state, &moderator, &command, params).unwrap().err(),
+               Some(Error::NotOrganizer));
+    assert_eq!(texts(&run(&state, 1, "!cancel-race").unwrap()),
+               ["The race got cancelled."]);
+    assert_eq!(run(&state, 1, "!entrants").err(), Some(Error::NoRace));
+}
+
+#[test]
+fn leave_race() {
+    let state = test_state("leave-race");
+    run(&state, 1, "!create-race").unwrap();
+    run(&state, 2, "!enter").unwrap();
+    assert_eq!(run(&state, 2, "!unready").err(), Some(Error::NotReady));
+    run(&state, 2, "!ready").unwrap();
+    assert_eq!(texts(&run(&state, 2, "!unready").unwrap()),
+               ["You are no longer ready for the race."]);
+    run(&state, 2, "!ready").unwrap();
+
+    let replies = run(&state, 1, "!leave").unwrap();
+    assert!(countdown(&replies).is_some());
+    start_race(&invoker(2), &state).unwrap();
+
+    assert_eq!(run(&state, 1, "!leave").err(), Some(Error::NotEntered));
+    let replies = run(&state, 2, "!leave").unwrap();
+    assert_eq!(texts(&replies)[0], "Runner 2 forfeited the race.");
+    assert_eq!(texts(&replies)[1],
+               "The race is over! Results of Wind Waker - Any%:\n-. Runner 2 (Forfeited)");
+    assert_eq!(expire_race(&state), None);
+
+    run(&state, 1, "!create-race").unwrap();
+    assert_eq!(texts(&run(&state, 1, "!leave").unwrap())[1],
+               "Everyone left, so the race got cancelled.");
+}
+
+#[test]
+fn race_timeouts() {
+    let age = |state: &LSState| match *state.race.write() {
+        Race::Setup(ref mut setup) |
+        Race::Countdown(ref mut setup) => {
+            setup.created = Instant::now() - Duration::from_secs(24 * 60 * 60);
+        }
+        _ => {}
+    };
+
+    let state = test_state("race-timeouts");
+    run(&state, 1, "!create-race").unwrap();
+    assert_eq!(expire_race(&state), None);
+    age(&state);
+    assert_eq!(expire_race(&state),
+               Some((1, String::from("The race timed out and got cancelled."))));
+    assert_eq!(run(&state, 1, "!entrants").err(), Some(Error::NoRace));
+
+    run(&state, 1, "!create-race").unwrap();
+    run(&state, 1, "!ready").unwrap();
+    assert_eq!(expire_race(&state), None);
+    age(&state);
+    assert_eq!(expire_race(&state),
+               Some((1, String::from("The race timed out and got cancelled."))));
+
+    let state = LSState::new(Config {
+                                 storage_path: String::from("target/test-storage/race-timeouts"),
+                                 setup_timeout: 0,
+                                 ..Config::default()
+                             });
+    run(&state, 1, "!create-race").unwrap();
+    age(&state);
+    assert_eq!(expire_race(&state), None);
+}
+
+#[test]
+fn history_and_records() {
+    let state = test_state("history");
+    assert_eq!(texts(&run(&state, 1, "!race-history").unwrap()),
+               ["No races were run yet."]);
+
+    run(&state, 1, "!create-race").unwrap();
+    run(&state, 2, "!enter").unwrap();
+    run(&state, 1, "!force-start").unwrap();
+    start_race(&invoker(1), &state).unwrap();
+    run(&state, 2, "!done").unwrap();
+    run(&state, 1, "!leave").unwrap();
+
+    let history = texts(&run(&state, 1, "!race-history").unwrap())[0].to_owned();
+    assert!(history.starts_with("Recent races:\n#1 "));
+    assert!(history.contains("Wind Waker - Any%: won by Runner 2 in 0:00:00."));
+
+    assert!(match run(&state, 1, "!race 1").unwrap()[0] {
+                Reply::Embed(ref embed) => {
+                    embed.title == "Race #1: Wind Waker - Any%" &&
+                    embed.description.ends_with("-. Runner 1 (Forfeited)")
+                }
+                _ => false,
+            });
+    assert_eq!(run(&state, 1, "!race 2").err(), Some(Error::RaceNotFound(2)));
+
+    assert_eq!(texts(&run(&state, 3, "!race-record runner 2").unwrap()),
+               ["Runner 2: 1 races, 1 wins, 0 second places, 0 third places, 1 finished, 0 \
+                 forfeited"]);
+    assert_eq!(texts(&run(&state, 3, "!race-record").unwrap()),
+               ["Runner 3 didn't run any races yet."]);
+
+    assert_eq!(texts(&run(&state, 2, "!rating").unwrap()),
+               ["Ratings of Runner 2:\nWind Waker - Any%: 1516 (#1 of 2, 1 races)"]);
+    assert_eq!(texts(&run(&state, 2, "!rating <@3>").unwrap()),
+               ["<@3> isn't rated yet."]);
+    assert_eq!(texts(&run(&state, 3, "!leaderboard wind waker any%").unwrap()),
+               ["**Wind Waker - Any%**\n1. Runner 2 1516 (1 races)\n2. Runner 1 1484 (1 \
+                 races)"]);
+    assert_eq!(texts(&run(&state, 3, "!leaderboard Super Metroid | 100%").unwrap()),
+               ["Nobody is rated in Super Metroid | 100% yet."]);
+}
+
+#[test]
+fn race_goals() {
+    let state = test_state("race-goals");
+    assert_eq!(texts(&run(&state, 1, "!create-race Super Metroid | 100% | Beat Ridley").unwrap()),
+               ["Created a new race!\nSuper Metroid - 100%: Beat Ridley"]);
+    run(&state, 2, "!enter").unwrap();
+    assert_eq!(texts(&run(&state, 2, "!entrants").unwrap()),
+               ["Super Metroid - 100%: Beat Ridley\nEntrants: Runner 1 (Not Ready), Runner 2 (Not \
+                 Ready)"]);
+
+    run(&state, 2, "!load-race-splits").unwrap();
+    {
+        let runner = state.users.get(&2).unwrap();
+        assert_eq!(runner.timer.run().game_name(), "Super Metroid");
+        assert_eq!(runner.timer.run().category_name(), "100%");
+    }
+
+    run(&state, 1, "!force-start").unwrap();
+    start_race(&invoker(1), &state).unwrap();
+    run(&state, 1, "!leave").unwrap();
+    assert!(texts(&run(&state, 2, "!done").unwrap())[1]
+                .starts_with("The race is over! Results of Super Metroid - 100%:\nGoal: Beat \
+                              Ridley\n1. Runner 2 ("));
+}
+
+#[test]
+fn scheduled_races() {
+    let state = test_state("scheduled-races");
+    assert_eq!(run(&state, 1, "!schedule-race 1h").err(), Some(Error::NotOrganizer));
+    let mut organizer = invoker(1);
+    organizer.manage_guild = true;
+    let schedule = |message: &str| {
+        let (command, params) = parse("!", message).unwrap();
+        execute(&state, &organizer, &command, params).unwrap()
+    };
+    assert_eq!(schedule("!schedule-race soon").err(),
+               Some(Error::Usage("!schedule-race <1h30m | HH:MM> [<game> | <category> | <goal>]")));
+    assert_eq!(schedule("!schedule-race 3h1m").err(), Some(Error::TooFarAhead(3)));
+    let replies = schedule("!schedule-race 1h Wind Waker | Any%").unwrap();
+    assert!(texts(&replies)[0].starts_with("Scheduled a new race for "));
+    assert!(texts(&replies)[0].ends_with(" UTC!\nWind Waker - Any%"));
+
+    run(&state, 2, "!enter").unwrap();
+    run(&state, 1, "!ready").unwrap();
+    assert_eq!(run(&state, 2, "!ready").unwrap().len(), 1);
+    assert!(remind_entrants(&state).is_none());
+    assert!(start_scheduled_race(&state).is_none());
+
+    let reschedule = |minutes| if let Race::Setup(ref mut setup) = *state.race.write() {
+        setup.start_at = Some(history::now() + minutes * 60);
+    };
+    reschedule(5);
+    let (invoker, replies) = remind_entrants(&state).unwrap();
+    assert_eq!(invoker.channel, 1);
+    assert_eq!(texts(&replies),
+               ["Reminder: Race #1 starts in 5 minutes! Entrants: Runner 1, Runner 2"]);
+    assert_eq!(replies
+                   .iter()
+                   .filter(|reply| match **reply {
+                               Reply::Direct(_, ref text) => {
+                                   text == "Reminder: Race #1 starts in 5 minutes!"
+                               }
+                               _ => false,
+                           })
+                   .count(),
+               2);
+    assert!(remind_entrants(&state).is_none());
+
+    reschedule(0);
+    run(&state, 2, "!unready").unwrap();
+    let (invoker, replies) = start_scheduled_race(&state).unwrap();
+    assert_eq!(invoker.id, 1);
+    assert_eq!(texts(&replies),
+               ["It's time for the race! Waiting for Runner 2 to get ready."]);
+    assert!(start_scheduled_race(&state).is_none());
+    assert_eq!(countdown(&run(&state, 2, "!ready").unwrap()), Some(10));
+}
+
+#[test]
+fn team_races() {
+    let state = test_state("team-races");
+    run(&state, 1, "!create-race").unwrap();
+    assert_eq!(run(&state, 2, "!enter team Red").err(), Some(Error::NotTeamRace));
+    run(&state, 1, "!cancel-race").unwrap();
+
+    assert_eq!(texts(&run(&state, 1, "!create-race relay").unwrap()),
+               ["Created a new relay race!\nJoin a team with `!enter team <name>`."]);
+    assert_eq!(run(&state, 1, "!enter").err(),
+               Some(Error::Usage("!enter team <name>")));
+    assert_eq!(texts(&run(&state, 1, "!enter team Red").unwrap()),
+               ["You successfully entered the race for Red!"]);
+    assert_eq!(texts(&run(&state, 2, "!enter team red").unwrap()),
+               ["You successfully entered the race for Red!"]);
+    run(&state, 3, "!enter team Blue").unwrap();
+    assert_eq!(texts(&run(&state, 3, "!entrants").unwrap()),
+               ["Entrants: Runner 1 [Red] (Not Ready), Runner 2 [Red] (Not Ready), Runner 3 \
+                 [Blue] (Not Ready)"]);
+
+    run(&state, 1, "!ready").unwrap();
+    run(&state, 2, "!ready").unwrap();
+    run(&state, 3, "!ready").unwrap();
+    start_race(&invoker(1), &state).unwrap();
+
+    assert_eq!(run(&state, 2, "!done").err(), Some(Error::NotRunning));
+    assert_eq!(texts(&run(&state, 1, "!done").unwrap())[1],
+               "Runner 2 takes over, go!");
+    run(&state, 2, "!done").unwrap();
+    let results = texts(&run(&state, 3, "!leave").unwrap())[1].to_owned();
+    assert!(results.starts_with("The race is over! Results of Wind Waker - Any%:\n1. Red ("));
+    assert!(results.contains("\n  Runner 1 ("));
+    assert!(results.ends_with("\n-. Blue (Did not finish)\n  Runner 3 (Forfeited)"));
+}
+
+#[test]
+fn kick_relay_runner() {
+    let state = test_state("kick-relay-runner");
+    run(&state, 1, "!create-race relay").unwrap();
+    run(&state, 1, "!enter team Red").unwrap();
+    run(&state, 2, "!enter team Red").unwrap();
+    run(&state, 3, "!enter team Blue").unwrap();
+    run(&state, 1, "!ready").unwrap();
+    run(&state, 2, "!ready").unwrap();
+    run(&state, 3, "!ready").unwrap();
+    start_race(&invoker(1), &state).unwrap();
+
+    assert_eq!(texts(&run(&state, 1, "!kick runner 2").unwrap()),
+               ["Runner 2 got removed from the race."]);
+    assert_eq!(run(&state, 1, "!done").err(), Some(Error::NotRunning));
+    let results = texts(&run(&state, 3, "!done").unwrap())[1].to_owned();
+    assert!(results.ends_with("\n-. Red (Did not finish)\n  Runner 1 (Forfeited)\n  Runner 2 \
+                               (Forfeited)"));
+}
+
+#[test]
+fn watch_race() {
+    let state = test_state("watch-race");
+    assert_eq!(run(&state, 3, "!watch").err(), Some(Error::NoRace));
+    run(&state, 1, "!create-race").unwrap();
+    run(&state, 3, "!watch").unwrap();
+    assert_eq!(run(&state, 3, "!watch").err(), Some(Error::AlreadyWatching));
+    assert_eq!(run(&state, 4, "!unwatch").err(), Some(Error::NotWatching));
+
+    let directs = |replies: &[Reply]| {
+        replies
+            .iter()
+            .filter_map(|reply| match *reply {
+                            Reply::Direct(user, ref text) => Some((user, text.clone())),
+                            _ => None,
+                        })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(directs(&run(&state, 1, "!ready").unwrap()),
+               [(1, String::from("The countdown for race #1 started, get ready!"))]);
+
+    let replies = start_race(&invoker(1), &state).unwrap();
+    assert_eq!(directs(&replies), [(3, String::from("Race #1 started!"))]);
+
+    let replies = run(&state, 1, "!done").unwrap();
+    let directs = directs(&replies);
+    assert_eq!(directs.len(), 2);
+    assert!(directs[0].1.starts_with("Race #1: Runner 1 finished the race in"));
+    assert!(directs[1].1.starts_with("Race #1: The race is over!"));
+
+    run(&state, 1, "!create-race").unwrap();
+    run(&state, 3, "!watch").unwrap();
+    assert_eq!(texts(&run(&state, 3, "!unwatch").unwrap()),
+               ["You are no longer watching the race."]);
+}
+
+#[test]
+fn compare_race_splits() {
+    let state = test_state("race-splits");
+    let seconds = Duration::from_secs;
+    let entrant = |id, splits: Vec<u64>, time| {
+        EntrantRecord {
+            id: id,
+            name: format!("Runner {}", id),
+            place: None,
+            time: time,
+            forfeited: false,
+            team: None,
+            splits: splits.into_iter().map(seconds).collect(),
+        }
+    };
+    state
+        .history
+        .write()
+        .push(RaceRecord {
+                  id: 1,
+                  game: String::from("Wind Waker"),
+                  category: String::from("Any%"),
+                  goal: None,
+                  date: 0,
+                  entrants: vec![entrant(1, vec![60, 100], Some(seconds(150))),
+                                 entrant(2, vec![50, 120], None)],
+                  teams: Vec::new(),
+              });
+
+    assert_eq!(run(&state, 1, "!race-splits").err(),
+               Some(Error::Usage("!race-splits <id>")));
+    assert_eq!(run(&state, 1, "!race-splits 2").err(), Some(Error::RaceNotFound(2)));
+    let fields = match run(&state, 1, "!race-splits #1").unwrap().pop() {
+        Some(Reply::Embed(embed)) => embed.fields,
+        _ => panic!("The splits should be an embed"),
+    };
+    assert_eq!(fields,
+               [(String::from("Split 1"),
+                 String::from("Runner 1  0:01:00.00 (0:01:00.00)\n**Runner 2  0:00:50.00 \
+                               (0:00:50.00)**")),
+                (String::from("Split 2"),
+                 String::from("**Runner 1  0:01:40.00 (0:00:40.00)**\nRunner 2  0:02:00.00 \
+                               (0:01:10.00)")),
+                (String::from("Finish"),
+                 String::from("**Runner 1  0:02:30.00 (0:00:50.00)**"))]);
+}
+
+#[test]
+fn async_race_window() {
+    let state = test_state("async-races");
+    assert_eq!(run(&state, 2, "!async-start").err(), Some(Error::NoRace));
+    assert_eq!(run(&state, 1, "!create-async whenever").err(),
+               Some(Error::Usage("!create-async <1h30m | HH:MM> [<game> | <category> | <goal>]")));
+    let replies = run(&state, 1, "!create-async 2h Wind Waker | Any% | Beat Ganon").unwrap();
+    assert!(texts(&replies)[0].starts_with("Created async race #1: Wind Waker - Any%: Beat \
+                                            Ganon\nStart your run with `!async-start 1` before \
+                                            it closes at "));
+
+    assert!(match run(&state, 2, "!async-start").unwrap().pop() {
+                Some(Reply::AsyncCountdown { race: 1, seconds: 10 }) => true,
+                _ => false,
+            });
+    assert_eq!(run(&state, 2, "!async-start #1").err(), Some(Error::AlreadyEntered));
+    assert_eq!(run(&state, 2, "!async-done").err(), Some(Error::NotRunning));
+    assert_eq!(texts(&start_async_run(&invoker(2), 1, &state).unwrap()),
+               ["Runner 2 started their run of async race #1!"]);
+    run(&state, 3, "!async-start").unwrap();
+    assert_eq!(texts(&run(&state, 3, "!async-forfeit 1").unwrap()),
+               ["Runner 3 forfeited async race #1."]);
+    assert!(texts(&run(&state, 1, "!async-races").unwrap())[0]
+                .starts_with("Open async races:\n#1 Wind Waker - Any% (2 entrants), closes at "));
+
+    assert_eq!(texts(&run(&state, 2, "!async-done").unwrap()),
+               ["Runner 2 finished their run of async race #1."]);
+    assert!(close_async_races(&state).is_empty());
+
+    state.async_races.write()[0].closes_at = history::now();
+    let announcements = close_async_races(&state);
+    assert_eq!(announcements.len(), 1);
+    assert_eq!(announcements[0].0, 1);
+    assert!(announcements[0]
+                .1
+                .starts_with("Async race #1 is closed! Results of Wind Waker - Any%:\nGoal: Beat \
+                              Ganon\n1. Runner 2 ("));
+    assert_eq!(state.history.read().len(), 1);
+    assert_eq!(texts(&run(&state, 1, "!async-races").unwrap()),
+               ["There are no open async races."]);
+}
+
+#[test]
+fn async_bingo_race() {
+    let state = test_state("async-bingo-race");
+    let replies = run(&state, 1, "!create-async 2h lockout short").unwrap();
+    assert!(texts(&replies)[0].starts_with("Created async bingo race #1"));
+    run(&state, 2, "!async-start").unwrap();
+    assert_eq!(run(&state, 2, "!mark 2 1").err(), Some(Error::NoRace));
+
+    let replies = start_async_run(&invoker(2), 1, &state).unwrap();
+    assert!(match replies[1] {
+                Reply::Direct(2, ref text) => text.starts_with("Your board for async race #1: "),
+                _ => false,
+            });
+    for col in 1..5 {
+        let replies = run(&state, 2, &format!("!mark 2 {}", col)).unwrap();
+        assert!(texts(&replies)[0].starts_with("Marked"));
+    }
+    assert!(match run(&state, 2, "!mark 2 1") {
+                Err(Error::AlreadyMarked(_)) => true,
+                _ => false,
+            });
+
+    let replies = run(&state, 2, "!mark 2 5").unwrap();
+    assert_eq!(texts(&replies)[1], "Runner 2 finished their run of async race #1.");
+    assert_eq!(run(&state, 2, "!async-done").err(), Some(Error::BingoRace));
+}
+
+#[test]
+fn bingo_styles() {
+    let state = test_state("bingo-styles");
+    assert_eq!(run(&state, 1, "!bingo-style bg=#102030").err(),
+               Some(Error::NotModerator));
+    let default_style = run(&state, 1, "!bingo-style").unwrap();
+    assert_eq!(texts(&default_style).len(), 1);
+
+    let mut moderator = invoker(2);
+    moderator.manage_guild = true;
+    let (command, params) = parse("!", "!bingo-style bg=#102030").unwrap();
+    assert!(execute(&state, &moderator, &command, params).unwrap().is_ok());
+    let style = run(&state, 1, "!bingo-style").unwrap();
+    assert!(texts(&style) != texts(&default_style));
+
+    // The style is kept with the guild settings.
+    let state = LSState::new(Config {
+                                 storage_path: String::from("target/test-storage/bingo-styles"),
+                                 ..Config::default()
+                             });
+    assert_eq!(texts(&run(&state, 1, "!bingo-style").unwrap()), texts(&style));
+}
+
+#[test]
+fn livesplit_splits_count_in_races() {
+    let state = test_state("livesplit-splits");
+    run(&state, 1, "!create-race").unwrap();
+    run(&state, 2, "!enter").unwrap();
+    run(&state, 2, "!ready").unwrap();
+    run(&state, 1, "!ready").unwrap();
+    start_race(&invoker(1), &state).unwrap();
+
+    assert_eq!(::tcp_server::execute(&state, 2, "split"), None);
+    match *state.race.read() {
+        Race::InProgress(ref race) => {
+            let splits = race.entrants
+                .iter()
+                .find(|e| e.id == 2)
+                .map(|e| e.splits.len());
+            assert_eq!(splits, Some(1));
+        }
+        _ => panic!("The race should be in progress"),
+    }
+}
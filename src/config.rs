@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+use dotenv::var;
+use livesplit_core::{Run, Segment};
+use livesplit_core::parser::composite;
+use toml;
+use error::Error;
+
+const DEFAULT_PATH: &'static str = "config.toml";
+
+/// The settings of the bot, read from `config.toml` or the file `CONFIG_FILE`
+/// points to. Every setting is optional.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The prefix commands start with.
+    pub prefix: String,
+    /// How many seconds to count down before a race starts.
+    pub countdown: u64,
+    /// How many minutes a race can be set up before it gets cancelled. Zero
+    /// disables the timeout.
+    pub setup_timeout: u64,
+    /// How many minutes a race can be in progress before it gets cancelled.
+    pub race_timeout: u64,
+    /// How many minutes before a scheduled race starts its entrants get
+    /// reminded. Zero disables the reminders.
+    pub reminder: u64,
+    /// The URL the REST API is reachable at.
+    pub public_url: String,
+    /// The host and port the LiveSplit Server is reachable at, which users
+    /// connect their LiveSplit Server component to.
+    pub livesplit_server_address: String,
+    /// The directory persistent data is stored in.
+    pub storage_path: String,
+    /// A directory with additional bingo templates, one `<name>.json` each.
+    pub bingo_templates: Option<String>,
+    /// Commands that are ignored, like `load-splits`.
+    pub disabled_commands: Vec<String>,
+    pub modules: Modules,
+    /// The run new users start out with.
+    pub default_run: DefaultRun,
+}
+
+/// Which frontends to start.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Modules {
+    pub discord: bool,
+    pub irc: bool,
+    pub rest_api: bool,
+    pub livesplit_server: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct DefaultRun {
+    pub game: String,
+    pub category: String,
+    pub segments: Vec<String>,
+    /// A splits file to use instead of the segments above.
+    pub splits: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prefix: String::from("!"),
+            countdown: 10,
+            setup_timeout: 60,
+            race_timeout: 24 * 60,
+            reminder: 10,
+            public_url: String::from("http://localhost:8000"),
+            livesplit_server_address: String::from("localhost:16834"),
+            storage_path: String::from("data"),
+            bingo_templates: None,
+            disabled_commands: Vec::new(),
+            modules: Modules::default(),
+            default_run: DefaultRun::default(),
+        }
+    }
+}
+
+impl Default for Modules {
+    fn default() -> Self {
+        Modules {
+            discord: true,
+            irc: true,
+            rest_api: true,
+            livesplit_server: true,
+        }
+    }
+}
+
+impl Default for DefaultRun {
+    fn default() -> Self {
+        DefaultRun {
+            game: String::from("Wind Waker"),
+            category: String::from("Any%"),
+            segments: ["First", "Second", "Third", "End"]
+                .iter()
+                .map(|&s| String::from(s))
+                .collect(),
+            splits: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file, if there is one, and applies the overrides from
+    /// the environment variables `COMMAND_PREFIX`, `COUNTDOWN`, `PUBLIC_URL`,
+    /// `LIVESPLIT_SERVER_PUBLIC_ADDRESS`, `STORAGE_PATH` and `BINGO_TEMPLATES`.
+    pub fn load() -> Result<Config, Error> {
+        let path = var("CONFIG_FILE").unwrap_or_else(|_| String::from(DEFAULT_PATH));
+        let mut contents = String::new();
+        let mut config = match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)
+                    .map_err(|e| Error::Config(format!("Couldn't read {}: {}", path, e)))?;
+                Config::parse(&contents)
+                    .map_err(|e| Error::Config(format!("Invalid {}: {}", path, e)))?
+            }
+            Err(_) => {
+                info!("No config file found at {}, using the defaults", path);
+                Config::default()
+            }
+        };
+
+        if let Ok(prefix) = var("COMMAND_PREFIX") {
+            config.prefix = prefix;
+        }
+        if let Ok(countdown) = var("COUNTDOWN") {
+            config.countdown = countdown
+                .parse()
+                .map_err(|_| Error::Config(format!("Invalid COUNTDOWN `{}`", countdown)))?;
+        }
+        if let Ok(public_url) = var("PUBLIC_URL") {
+            config.public_url = public_url;
+        } else if !Config::sets(&contents, "public_url") {
+            if let Ok(port) = var("PORT") {
+                config.public_url = format!("http://localhost:{}", port);
+            }
+        }
+        if let Ok(address) = var("LIVESPLIT_SERVER_PUBLIC_ADDRESS") {
+            config.livesplit_server_address = address;
+        } else if !Config::sets(&contents, "livesplit_server_address") {
+            if let Ok(address) = var("LIVESPLIT_SERVER_ADDRESS") {
+                if let Some(port) = address.rsplit(':').next() {
+                    config.livesplit_server_address = format!("localhost:{}", port);
+                }
+            }
+        }
+        if let Ok(storage_path) = var("STORAGE_PATH") {
+            config.storage_path = storage_path;
+        }
+        if let Ok(bingo_templates) = var("BINGO_TEMPLATES") {
+            config.bingo_templates = Some(bingo_templates);
+        }
+
+        let public_url = config.public_url.trim_right_matches('/').len();
+        config.public_url.truncate(public_url);
+
+        Ok(config)
+    }
+
+    fn parse(contents: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    /// Whether the config file sets the setting instead of using its default.
+    fn sets(contents: &str, setting: &str) -> bool {
+        contents
+            .parse::<toml::Value>()
+            .ok()
+            .and_then(|value| value.as_table().map(|t| t.contains_key(setting)))
+            .unwrap_or(false)
+    }
+
+    pub fn is_enabled(&self, command: &str) -> bool {
+        !self.disabled_commands.iter().any(|c| c == command)
+    }
+}
+
+impl DefaultRun {
+    pub fn run(&self) -> Run {
+        if let Some(ref path) = self.splits {
+            let parsed = File::open(path)
+                .map_err(|e| e.to_string())
+                .and_then(|file| {
+                              let path = Some(PathBuf::from(path));
+                              composite::parse(BufReader::new(file), path, false)
+                                  .map_err(|e| format!("{:?}", e))
+                          });
+            match parsed {
+                Ok(run) => return run,
+                Err(e) => warn!("Couldn't load the default splits {}: {}", path, e),
+            }
+        }
+
+        let mut run = Run::new();
+        for segment in &self.segments {
+            run.push_segment(Segment::new(segment.as_str()));
+        }
+        run.set_game_name(self.game.as_str());
+        run.set_category_name(self.category.as_str());
+        run
+    }
+}
+
+#[test]
+fn parse_config() {
+    let config = Config::parse(r#"
+prefix = "?"
+disabled_commands = ["load-splits"]
+
+[modules]
+irc = false
+
+[default_run]
+game = "Breath of the Wild"
+segments = ["Plateau", "Ganon"]
+"#)
+            .unwrap();
+
+    assert_eq!(config.prefix, "?");
+    assert!(!Config::sets("# public_url = \"x\"", "public_url"));
+    assert_eq!(config.countdown, 10);
+    assert!(!config.is_enabled("load-splits"));
+    assert!(config.is_enabled("split"));
+    assert!(!config.modules.irc);
+    assert!(config.modules.discord);
+
+    let run = config.default_run.run();
+    assert_eq!(run.game_name(), "Breath of the Wild");
+    assert_eq!(run.category_name(), "Any%");
+    assert_eq!(run.len(), 2);
+}
@@ -1,61 +1,22 @@
-use std::borrow::Cow;
-use std::fmt::Write;
-use std::io::{Read, Cursor};
+use std::io::Cursor;
 use std::sync::Arc;
-use {LSState, User, Race};
-use chashmap::WriteGuard;
-use hyper_rustls::TlsClient;
-use hyper::Client as HyperClient;
-use hyper::net::HttpsConnector;
-use livesplit_core::{Color, Timer};
-use livesplit_core::parser::composite;
+use {FIRST_IDENTITY, LSState, Recipient, supervisor};
+use commands::{self, Embed, Invoker, Reply};
+use error::Error;
 use serenity::Client;
-use serenity::client::Context;
-use serenity::model::Message;
+use serenity::client::CACHE;
+use serenity::model::{ChannelId, Message, UserId};
 use serenity::utils::builder::CreateEmbed;
 use serenity::utils::Colour;
-use std::{thread, time};
+use std::collections::HashMap;
+use parking_lot::Mutex;
+use std::thread;
+use std::time::Duration;
 use dotenv::var;
-use speedrun_bingo::{self, Mode, Template};
-use rand::{Rng, thread_rng};
-use image::png::PNGEncoder;
-use image::ColorType;
 
-fn send_embed_message<F>(message: &Message, create: F) -> Result<(), String>
-    where F: FnOnce(CreateEmbed) -> CreateEmbed
-{
-    message
-        .channel_id
-        .send_message(|m| m.embed(create))
-        .map_err(|_| String::from("Couldn't send message"))?;
-    Ok(())
-}
-
-fn send_editable_text_message(message: &Message, text: &str) -> Result<Message, String> {
-    message
-        .channel_id
-        .send_message(|m| m.content(text))
-        .map_err(|_| String::from("Couldn't send message"))
-}
-
-fn send_text_message(message: &Message, text: &str) -> Result<(), String> {
-    send_editable_text_message(message, text)?;
-    Ok(())
-}
-
-fn layout(user: &mut User, embed: CreateEmbed) -> CreateEmbed {
-    let layout = user.eval_layout();
-    let (r, g, b) = match layout.timer.color {
-        Color::AheadGainingTime => (0x00, 0xcc, 0x4b),
-        Color::AheadLosingTime => (0x5c, 0xd6, 0x89),
-        Color::BehindGainingTime => (0xd6, 0x5c, 0x5c),
-        Color::BehindLosingTime => (0xcc, 0x00, 0x00),
-        Color::BestSegment => (0xff, 0xd4, 0x00),
-        Color::NotRunning | Color::Default => (0x99, 0x99, 0x99),
-        Color::Paused => (0x66, 0x66, 0x66),
-        Color::PersonalBest => (0x4d, 0xa6, 0xff),
-    };
-    let mut embed = embed
+fn embed(embed: &Embed, create: CreateEmbed) -> CreateEmbed {
+    let (r, g, b) = embed.colour;
+    let mut create = create
         .author(|a| {
                     a.name("LiveSplit")
                         .icon_url("https://raw.githubusercontent.\
@@ -63,412 +24,267 @@ fn layout(user: &mut User, embed: CreateEmbed) -> CreateEmbed {
                         .url("http://livesplit.org")
                 })
         .colour(Colour::from_rgb(r, g, b))
-        .description(&format!(r"
-**{}{}**
-
-**{}:**   {}
-**{}:**   {}
-**{}:**   {}
-**Attempts:**   {}",
-                              layout.timer.time,
-                              layout.timer.fraction,
-                              layout.previous_segment.text,
-                              layout.previous_segment.time,
-                              layout.sum_of_best.text,
-                              layout.sum_of_best.time,
-                              layout.possible_time_save.text,
-                              layout.possible_time_save.time,
-                              layout.title.attempts))
-        .title(&format!("{} - {}", layout.title.game, layout.title.category));
+        .description(&embed.description)
+        .title(&embed.title);
 
-    for segment in &layout.splits.splits {
-        embed = embed.field(|f| {
-                                f.name(&segment.name)
-                                    .value(&format!("{}  {}", segment.delta, segment.time))
-                            });
+    for &(ref name, ref value) in &embed.fields {
+        create = create.field(|f| f.name(name).value(value));
     }
 
-    embed
+    create
 }
 
-fn user<'a>(state: &'a LSState, message: &Message) -> WriteGuard<'a, u64, User> {
-    state.user(message.author.id.0, message.author.name.as_str())
+fn send_editable_text_message(message: &Message, text: &str) -> Result<Message, Error> {
+    message
+        .channel_id
+        .send_message(|m| m.content(text))
+        .map_err(|e| Error::Transport(format!("Couldn't send message: {:?}", e)))
 }
 
-fn split(_: &mut Context,
-         message: &Message,
-         _: Vec<String>,
-         state: &LSState)
-         -> Result<(), String> {
-    let mut user = user(state, message);
-    user.timer.split();
-    send_embed_message(message, |m| layout(&mut user, m))
-}
+fn countdown(message: &Message, heading: &str, seconds: u64) -> Result<(), Error> {
+    let mut countdown = send_editable_text_message(message, heading)?;
 
-fn reset(_: &mut Context,
-         message: &Message,
-         _: Vec<String>,
-         state: &LSState)
-         -> Result<(), String> {
-    let mut user = user(state, message);
-    user.timer.reset(true);
-    send_embed_message(message, |m| layout(&mut user, m))
-}
+    thread::sleep(Duration::from_secs(3));
+    for remaining_seconds in (1..seconds + 1).rev() {
+        countdown
+            .edit(&format!("**{}**", remaining_seconds), |x| x)
+            .map_err(|e| Error::Transport(format!("Couldn't edit message: {:?}", e)))?;
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    countdown
+        .edit("**Go!**", |x| x)
+        .map_err(|e| Error::Transport(format!("Couldn't edit message: {:?}", e)))?;
 
-fn get_state(_: &mut Context,
-             message: &Message,
-             _: Vec<String>,
-             state: &LSState)
-             -> Result<(), String> {
-    let mut user = user(state, message);
-    send_embed_message(message, |m| layout(&mut user, m))
+    Ok(())
 }
 
+/// The status message of every race, which gets edited as the race goes on.
+type StatusMessages = Mutex<HashMap<u64, Message>>;
 
-fn load_race_splits(_: &mut Context,
-                    message: &Message,
-                    _: Vec<String>,
-                    state: &LSState)
-                    -> Result<(), String> {
-    let mut user = user(state, message);
-    let race = state.race.read();
-    match *race {
-        Race::NoRace => send_text_message(message, "There is no race!"),
-        Race::InProgress(_) => send_text_message(message, "The Race is already in Progress!"),
-        Race::Setup(ref entrants) => {
-            let id = entrants[0].0;
-            let master = state
-                .users
-                .get(&id)
-                .ok_or_else(|| String::from("User not found"))?;
-            user.timer = master.timer.clone();
-            send_embed_message(message, |m| layout(&mut user, m))
-        }
+fn unpin_status(race: u64, status_message: &Message) {
+    if let Err(e) = status_message.unpin() {
+        warn!("Couldn't unpin the status of race {}: {:?}", race, e);
     }
 }
 
-fn load_splits(_: &mut Context,
+fn send_status(statuses: &StatusMessages,
                message: &Message,
-               params: Vec<String>,
-               state: &LSState)
-               -> Result<(), String> {
-    let mut user = user(state, message);
-    if let Some(param) = params.get(0) {
-        let ssl = TlsClient::new();
-        let connector = HttpsConnector::new(ssl);
-        let client = HyperClient::with_connector(connector);
-        if let Ok(mut response) = client
-               .get(&format!("https://splits.io/{}/download/livesplit", param))
-               .send() {
-            let mut splits = Vec::new();
-            if let Ok(_) = response.read_to_end(&mut splits) {
-                if let Ok(run) = composite::parse(Cursor::new(splits), None, false) {
-                    user.timer = Timer::new(run);
-                }
+               race: u64,
+               status: &Embed,
+               finished: bool)
+               -> Result<(), Error> {
+    let mut statuses = statuses.lock();
+    // Only one race runs at a time, so earlier races that timed out without a
+    // final status are over as well.
+    let earlier = statuses.keys().filter(|&&id| id != race).cloned().collect::<Vec<_>>();
+    for id in earlier {
+        if let Some(status_message) = statuses.remove(&id) {
+            unpin_status(id, &status_message);
+        }
+    }
+
+    if let Some(mut status_message) = statuses.remove(&race) {
+        if status_message.edit("", |c| embed(status, c)).is_ok() {
+            if finished {
+                unpin_status(race, &status_message);
+            } else {
+                statuses.insert(race, status_message);
             }
+            return Ok(());
         }
+        unpin_status(race, &status_message);
     }
-    send_embed_message(message, |m| layout(&mut user, m))
-}
 
-fn create_race(_: &mut Context,
-               message: &Message,
-               _: Vec<String>,
-               state: &LSState)
-               -> Result<(), String> {
-    let mut race = state.race.write();
-    if let Race::NoRace = *race {
-        user(state, message); // Make sure the user exists
-        *race = Race::Setup(vec![(message.author.id.0, false)]);
-        send_text_message(message, "Created a new race!")
-    } else {
-        send_text_message(message, "There already is an active race!")
+    let status_message = message
+        .channel_id
+        .send_message(|m| m.embed(|c| embed(status, c)))
+        .map_err(|e| Error::Transport(format!("Couldn't send message: {:?}", e)))?;
+    // The final standings stay around as a regular message.
+    if !finished {
+        if let Err(e) = status_message.pin() {
+            warn!("Couldn't pin the status of race {}: {:?}", race, e);
+        }
+        statuses.insert(race, status_message);
     }
+    Ok(())
 }
 
-fn create_bingo(_: &mut Context,
+fn send_replies(state: &LSState,
+                statuses: &StatusMessages,
+                invoker: &Invoker,
                 message: &Message,
-                params: Vec<String>,
-                _: &LSState)
-                -> Result<(), String> {
-    let mut mode = (Mode::Normal, "normal");
-    let mut template = ("normal", include_str!("../bingo-templates/botw.json"));
-
-    for param in &params {
-        match &param.to_lowercase() as &str {
-            "short" => {
-                mode = (Mode::Short, "short");
+                replies: Vec<Reply>)
+                -> Result<(), Error> {
+    for reply in commands::countdowns_last(replies) {
+        match reply {
+            Reply::Text(text) => {
+                send_editable_text_message(message, &text)?;
             }
-            "long" => {
-                mode = (Mode::Long, "long");
+            Reply::Embed(e) => {
+                message
+                    .channel_id
+                    .send_message(|m| m.embed(|c| embed(&e, c)))
+                    .map_err(|e| Error::Transport(format!("Couldn't send message: {:?}", e)))?;
             }
-            "korok" => {
-                template = ("korok", include_str!("../bingo-templates/botw-korok.json"));
+            Reply::File {
+                name,
+                content,
+                text,
+            } => {
+                message
+                    .channel_id
+                    .send_file(Cursor::new(content), &name, |m| m.content(&text))
+                    .map_err(|e| Error::Transport(format!("Couldn't send message: {:?}", e)))?;
             }
-            "shrine" => {
-                template = ("shrine", include_str!("../bingo-templates/botw-shrine.json"));
+            Reply::Direct(user, text) => {
+                // Users of other frontends get the message there. Users may
+                // not accept direct messages either, which shouldn't keep the
+                // remaining replies from being sent.
+                let sent = user < FIRST_IDENTITY &&
+                           match UserId(user)
+                                     .create_dm_channel()
+                                     .and_then(|c| c.id.send_message(|m| m.content(&text))) {
+                               Ok(_) => true,
+                               Err(e) => {
+                                   warn!("Couldn't send a direct message to {}: {:?}", user, e);
+                                   false
+                               }
+                           };
+                if !sent {
+                    state.announce_direct(user, &text);
+                }
             }
-            "gp" | "plateau" => {
-                template = ("plateau", include_str!("../bingo-templates/botw-plateau.json"));
+            Reply::RaceStatus {
+                race,
+                embed: status,
+                finished,
+            } => send_status(statuses, message, race, &status, finished)?,
+            Reply::Countdown(seconds) => {
+                countdown(message, "All entrants are ready!", seconds)?;
+                let replies = commands::start_race(invoker, state)?;
+                send_replies(state, statuses, invoker, message, replies)?;
             }
-            "compendium" => {
-                template = ("compendium", include_str!("../bingo-templates/botw-compendium.json"));
+            Reply::AsyncCountdown { race, seconds } => {
+                countdown(message, &format!("Get ready for async race #{}!", race), seconds)?;
+                let replies = commands::start_async_run(invoker, race, state)?;
+                send_replies(state, statuses, invoker, message, replies)?;
             }
-            _ => {}
         }
     }
-
-    let (mode, mode_txt) = mode;
-    let (path, template) = template;
-
-    let template = Template::from_json_str(template).unwrap();
-
-    let mut rng = thread_rng();
-    let seed = rng.gen_range(0, 1_000_000);
-
-    let board = template.generate(seed, mode);
-
-    let mut board_text = format!("https://livesplit.herokuapp.com/botw/bingo/{}/index.\
-                                  html?seed={}&mode={}",
-                                 path,
-                                 seed,
-                                 mode_txt);
-
-    static FONT: &[u8] = include_bytes!("../Calamity-Bold.ttf");
-    const CELL_SIZE: u32 = 150;
-    const FONT_SIZE: f32 = 20.0;
-    const CELL_PADDING: i32 = 5;
-
-    let image = speedrun_bingo::render(&board, CELL_SIZE, CELL_PADDING, FONT, FONT_SIZE);
-
-    let mut buffer = Vec::new();
-    PNGEncoder::new(&mut buffer).encode(&image, image.width(), image.height(), ColorType::RGBA(8));
-
-    message
-        .channel_id
-        .send_file(Cursor::new(buffer), "bingo.png", |m| m.content(&board_text))
-        .map_err(|_| String::from("Couldn't send message"))?;
-
     Ok(())
 }
 
-fn entrants(_: &mut Context,
-            message: &Message,
-            _: Vec<String>,
-            state: &LSState)
-            -> Result<(), String> {
-    let race = state.race.read();
-    let text = match *race {
-        Race::NoRace => Cow::from("There is no race!"),
-        Race::Setup(ref entrants) => {
-            let mut message = String::new();
-            for &(entrant, status) in entrants {
-                if !message.is_empty() {
-                    message.push_str(", ");
-                } else {
-                    message.push_str("Entrants: ");
-                }
-                write!(message,
-                       "{} ({})",
-                       state
-                           .users
-                           .get(&entrant)
-                           .ok_or_else(|| String::from("User not found"))?
-                           .name,
-                       if status { "Ready" } else { "Not Ready" })
-                        .unwrap();
-            }
-            message.into()
-        }
-        Race::InProgress(ref entrants) => {
-            let mut message = String::new();
-            for &entrant in entrants {
-                if !message.is_empty() {
-                    message.push_str(", ");
-                } else {
-                    message.push_str("Entrants: ");
-                }
-                write!(message,
-                       "{}",
-                       state
-                           .users
-                           .get(&entrant)
-                           .ok_or_else(|| String::from("User not found"))?
-                           .name)
-                        .unwrap();
-            }
-            message.into()
-        }
+/// Whether the author of the message may manage the guild and the names of
+/// their roles, as far as the cache knows.
+fn permissions(message: &Message) -> (bool, Vec<String>) {
+    let cache = CACHE.read();
+    let guild = match (message.guild_id(), cache.as_ref()) {
+        (Some(id), Ok(cache)) => cache.get_guild(id),
+        _ => None,
     };
-    send_text_message(message, &text)
-}
-
-fn enter(_: &mut Context,
-         message: &Message,
-         _: Vec<String>,
-         state: &LSState)
-         -> Result<(), String> {
-    let mut race = state.race.write();
-    let text = match *race {
-        Race::NoRace => "There is no race!",
-        Race::Setup(ref mut entrants) => {
-            if entrants.iter().any(|&(id, _)| id == message.author.id.0) {
-                "You already entered the race!"
-            } else {
-                user(state, message); // Make sure the user exists
-                entrants.push((message.author.id.0, false));
-                "You successfully entered the race!"
-            }
-        }
-        Race::InProgress(_) => "The Race is already in Progress!",
+    let guild = match guild {
+        Some(guild) => guild,
+        None => return (false, Vec::new()),
     };
-    send_text_message(message, text)
-}
-
-fn ready(_: &mut Context,
-         message: &Message,
-         _: Vec<String>,
-         state: &LSState)
-         -> Result<(), String> {
-    let mut race = state.race.write();
-    let new_state = match *race {
-        Race::NoRace => return send_text_message(message, "There is no race!"),
-        Race::InProgress(_) => {
-            return send_text_message(message, "The Race is already in Progress!")
-        }
-        Race::Setup(ref mut entrants) => {
-            let response = if let Some(&mut (_, ref mut status)) =
-                entrants
-                    .iter_mut()
-                    .filter(|&&mut (id, _)| id == message.author.id.0)
-                    .next() {
-                if *status {
-                    "You are already ready!"
-                } else {
-                    *status = true;
-                    "You are now ready for the race!"
-                }
-            } else {
-                "You didn't enter the race!"
-            };
-            send_text_message(message, response)?;
-
-            let all_ready = entrants.iter().all(|&(_, status)| status);
-            if all_ready {
-                let mut message = send_editable_text_message(message, "All entrants are ready!")?;
 
-                let entrants = entrants.iter().map(|&(id, _)| id).collect();
+    let author = message.author.id;
+    let manage_guild = guild.owner_id == author ||
+                       guild
+                           .permissions_for(message.channel_id, author)
+                           .manage_guild();
+    let roles = guild
+        .members
+        .get(&author)
+        .map_or_else(Vec::new, |member| {
+            member
+                .roles
+                .iter()
+                .filter_map(|role| guild.roles.get(role))
+                .map(|role| role.name.clone())
+                .collect()
+        });
+
+    (manage_guild, roles)
+}
 
-                thread::sleep(time::Duration::from_secs(3));
-                for remaining_seconds in (1..11).rev() {
-                    message
-                        .edit(&format!("**{}**", remaining_seconds), |x| x)
-                        .map_err(|_| String::from("Couldn't edit message"))?;
-                    thread::sleep(time::Duration::from_secs(1));
-                }
+fn handle(state: &LSState, statuses: &StatusMessages, message: &Message) {
+    if message.author.bot {
+        return;
+    }
 
-                message
-                    .edit("**Go!**", |x| x)
-                    .map_err(|_| String::from("Couldn't edit message"))?;
+    let guild = message.guild_id().map_or(0, |id| id.0);
+    let (command, params) = match commands::parse(&state.prefix(guild), &message.content) {
+        Some(command) => command,
+        None => return,
+    };
 
-                for entrant in &entrants {
-                    let mut user = state
-                        .users
-                        .get_mut(entrant)
-                        .ok_or_else(|| String::from("User not found"))?;
-                    user.timer.split();
-                }
+    let (manage_guild, roles) = permissions(message);
+    let invoker = Invoker {
+        id: message.author.id.0,
+        name: message.author.name.clone(),
+        channel: message.channel_id.0,
+        guild: guild,
+        manage_guild: manage_guild,
+        roles: roles,
+    };
 
-                Race::InProgress(entrants)
-            } else {
-                return Ok(());
-            }
+    let result = match commands::execute(state, &invoker, &command, params) {
+        Some(result) => {
+            result.and_then(|replies| send_replies(state, statuses, &invoker, message, replies))
         }
+        None => return,
     };
-    *race = new_state;
-    Ok(())
-}
-
-fn done(_: &mut Context, _: &Message, _: Vec<String>, _: &LSState) -> Result<(), String> {
-    // let mut race = state.race.write();
-    // let new_state = match *race {
-    //     Race::NoRace => return send_text_message(message, "There is no race!"),
-    //     Race::InProgress(ref mut race_state) => {
 
-    //     }
-    //     Race::Setup(_) => {
-    //         return send_text_message(message, "The Race is currently being set up!")
-    //     }
-    // };
-    // *race = new_state;
-    Ok(())
+    if let Err(e) = result {
+        if !e.is_user_error() {
+            error!("Command `{}` by {} failed: {}", command, invoker.name, e);
+        }
+        if let Err(e) = send_editable_text_message(message, &e.user_message()) {
+            error!("Couldn't report the error to {}: {}", invoker.name, e);
+        }
+    }
 }
 
+/// Runs the Discord client, restarting it with an increasing delay whenever
+/// it disconnects.
 pub fn start(state: Arc<LSState>) {
-    let token = var("DISCORD_TOKEN").expect("Expected DISCORD_TOKEN Environment Variable");
-    let mut client = Client::login_bot(&token);
-
-    client.with_framework(move |f| {
-        let split_state = state.clone();
-        let reset_state = state.clone();
-        let load_splits_state = state.clone();
-        let load_race_splits_state = state.clone();
-        let create_race_state = state.clone();
-        let entrants_state = state.clone();
-        let enter_state = state.clone();
-        let ready_state = state.clone();
-        let create_bingo_state = state.clone();
-        let done_state = state.clone();
-
-        f.configure(|c| c.prefix("!"))
-            .on("split", move |c, m, v| split(c, m, v, &split_state))
-            .on("reset", move |c, m, v| reset(c, m, v, &reset_state))
-            .on("load-splits",
-                move |c, m, v| load_splits(c, m, v, &load_splits_state))
-            .on("load-race-splits",
-                move |c, m, v| load_race_splits(c, m, v, &load_race_splits_state))
-            .on("create-race",
-                move |c, m, v| create_race(c, m, v, &create_race_state))
-            .on("entrants",
-                move |c, m, v| entrants(c, m, v, &entrants_state))
-            .on("enter", move |c, m, v| enter(c, m, v, &enter_state))
-            .on("ready", move |c, m, v| ready(c, m, v, &ready_state))
-            .on("done", move |c, m, v| done(c, m, v, &done_state))
-            .on("timer", move |c, m, v| get_state(c, m, v, &state))
-            .on("bingo",
-                move |c, m, v| create_bingo(c, m, v, &create_bingo_state))
-    });
-
-    client.start().unwrap();
-}
-
-#[test]
-fn verify_bingo_board() {
-    let template = include_str!("../bingo-templates/botw.json");
-    let template = Template::from_json_str(template).unwrap();
-
-    let mut rng = thread_rng();
-    let seed = rng.gen_range(0, 1_000_000);
-
-    let board = template.generate(seed, Mode::Short);
-
-    static FONT: &[u8] = include_bytes!("../Calamity-Bold.ttf");
-    const CELL_SIZE: u32 = 150;
-    const FONT_SIZE: f32 = 20.0;
-    const CELL_PADDING: i32 = 5;
-
-    let image = speedrun_bingo::render(&board, CELL_SIZE, CELL_PADDING, FONT, FONT_SIZE);
-    image.save("test.png");
-
-    let mut goals = template
-        .0
-        .iter()
-        .flat_map(|r| r.iter())
-        .map(|g| &g.name)
-        .collect::<Vec<_>>();
-    goals.sort();
+    let token = match var("DISCORD_TOKEN") {
+        Ok(token) => token,
+        Err(_) => {
+            error!("Expected DISCORD_TOKEN Environment Variable");
+            return;
+        }
+    };
 
-    for (a, b) in goals.iter().zip(goals.iter().skip(1)) {
-        assert_ne!(a, b);
-    }
+    // Channels and users of other frontends get IDs Discord doesn't use.
+    state.add_announcer(Box::new(|_, recipient, text| match recipient {
+                                     Recipient::Channel(channel) => {
+                                         channel != 0 && channel < FIRST_IDENTITY &&
+                                         ChannelId(channel)
+                                             .send_message(|m| m.content(text))
+                                             .is_ok()
+                                     }
+                                     Recipient::User(user) => {
+                                         user != 0 && user < FIRST_IDENTITY &&
+                                         UserId(user)
+                                             .create_dm_channel()
+                                             .and_then(|c| {
+                                                           c.id.send_message(|m| m.content(text))
+                                                       })
+                                             .is_ok()
+                                     }
+                                 }));
+
+    let statuses = Arc::new(Mutex::new(HashMap::new()));
+    supervisor::supervise("Discord client", || {
+        let mut client = Client::login_bot(&token);
+        let (handler_state, handler_statuses) = (state.clone(), statuses.clone());
+        client.on_message(move |_, message| handle(&handler_state, &handler_statuses, &message));
+
+        match client.start() {
+            Ok(()) => warn!("The Discord client stopped"),
+            Err(e) => error!("The Discord client failed: {:?}", e),
+        }
+    })
 }
@@ -0,0 +1,139 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    NoRace,
+    RaceAlreadyExists,
+    RaceInProgress,
+    RaceNotStarted,
+    NotEntered,
+    AlreadyEntered,
+    AlreadyReady,
+    NotReady,
+    AlreadyFinished,
+    NotRunning,
+    NotBingoRace,
+    BingoRace,
+    GoalNotFound,
+    AlreadyMarked(String),
+    AlreadyClaimed { goal: String, owner: String },
+    UnknownRenderOption(String),
+    UnknownService(String),
+    InvalidVerificationCode,
+    NotModerator,
+    NotOrganizer,
+    EntrantNotFound(String),
+    EntryClosed,
+    /// The race would start later than the given number of hours from now.
+    TooFarAhead(u64),
+    NoEntrants,
+    RaceNotFound(u64),
+    NotTeamRace,
+    AlreadyWatching,
+    NotWatching,
+    UnknownSetting(String),
+    InvalidSetting(String),
+    RaceChannel,
+    TemplateDisabled(String),
+    /// There are no splits with the splits.io ID, or they can't be parsed.
+    SplitsNotFound(String),
+    /// The command is missing parameters. Contains how to use it.
+    Usage(&'static str),
+    /// The user is referenced by a race but doesn't exist.
+    UserNotFound(u64),
+    /// Persistent data couldn't be read or written.
+    Storage(String),
+    /// The configuration is invalid.
+    Config(String),
+    /// A frontend couldn't deliver or receive a message.
+    Transport(String),
+    Internal(String),
+}
+
+impl Error {
+    /// Whether the error is caused by the way the user invoked the command,
+    /// so the message can be shown to them as is.
+    pub fn is_user_error(&self) -> bool {
+        match *self {
+            Error::UserNotFound(_) |
+            Error::Storage(_) |
+            Error::Config(_) |
+            Error::Transport(_) |
+            Error::Internal(_) => false,
+            _ => true,
+        }
+    }
+
+    /// The message to reply to the user with.
+    pub fn user_message(&self) -> String {
+        if self.is_user_error() {
+            self.to_string()
+        } else {
+            String::from("Something went wrong, please try again later.")
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoRace => write!(f, "There is no race!"),
+            Error::RaceAlreadyExists => write!(f, "There already is an active race!"),
+            Error::RaceInProgress => write!(f, "The Race is already in Progress!"),
+            Error::RaceNotStarted => write!(f, "The Race is currently being set up!"),
+            Error::NotEntered => write!(f, "You didn't enter the race!"),
+            Error::AlreadyEntered => write!(f, "You already entered the race!"),
+            Error::AlreadyReady => write!(f, "You are already ready!"),
+            Error::NotReady => write!(f, "You are not ready yet!"),
+            Error::AlreadyFinished => write!(f, "You already finished the race!"),
+            Error::NotRunning => write!(f, "You are not running in the race!"),
+            Error::NotBingoRace => write!(f, "This is not a bingo race!"),
+            Error::BingoRace => write!(f, "Bingo races are won by marking the goals of a line!"),
+            Error::GoalNotFound => write!(f, "Couldn't find that goal on the board!"),
+            Error::AlreadyMarked(ref goal) => write!(f, "You already marked **{}**!", goal),
+            Error::AlreadyClaimed { ref goal, ref owner } => {
+                write!(f, "**{}** was already claimed by {}!", goal, owner)
+            }
+            Error::UnknownRenderOption(ref option) => {
+                write!(f, "Unknown render option `{}`!", option)
+            }
+            Error::UnknownService(ref service) => {
+                write!(f, "Unknown service `{}`! Try `twitch`.", service)
+            }
+            Error::InvalidVerificationCode => {
+                write!(f, "That verification code isn't valid for you!")
+            }
+            Error::NotModerator => {
+                write!(f, "Only moderators can change the settings of this server!")
+            }
+            Error::NotOrganizer => write!(f, "Only the race's organizers can do that!"),
+            Error::EntrantNotFound(ref user) => {
+                write!(f, "{} didn't enter the race!", user)
+            }
+            Error::EntryClosed => write!(f, "Entry to the race is closed!"),
+            Error::TooFarAhead(hours) => {
+                write!(f, "Races can only be scheduled up to {} hours ahead!", hours)
+            }
+            Error::NoEntrants => write!(f, "Nobody entered the race!"),
+            Error::RaceNotFound(id) => write!(f, "There is no race #{}!", id),
+            Error::NotTeamRace => write!(f, "This isn't a team race!"),
+            Error::AlreadyWatching => write!(f, "You are already watching the race!"),
+            Error::NotWatching => write!(f, "You aren't watching the race!"),
+            Error::UnknownSetting(ref key) => write!(f, "Unknown setting `{}`!", key),
+            Error::InvalidSetting(ref key) => write!(f, "Invalid value for `{}`!", key),
+            Error::RaceChannel => write!(f, "Races can't be run in this channel!"),
+            Error::TemplateDisabled(ref template) => {
+                write!(f, "The bingo template `{}` is disabled on this server!", template)
+            }
+            Error::SplitsNotFound(ref id) => {
+                write!(f, "Couldn't load the splits `{}` from splits.io!", id)
+            }
+            Error::Usage(usage) => write!(f, "Usage: `{}`", usage),
+            Error::UserNotFound(id) => write!(f, "User {} not found", id),
+            Error::Storage(ref cause) => write!(f, "Storage error: {}", cause),
+            Error::Config(ref cause) => write!(f, "Configuration error: {}", cause),
+            Error::Transport(ref cause) => write!(f, "Transport error: {}", cause),
+            Error::Internal(ref cause) => write!(f, "Internal error: {}", cause),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use error::Error;
+use storage;
+use race::format_time;
+
+const FILE_NAME: &'static str = "races.json";
+
+/// A finished race, as it is kept in the history.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RaceRecord {
+    pub id: u64,
+    pub game: String,
+    pub category: String,
+    pub goal: Option<String>,
+    /// When the race started, in seconds since the Unix epoch.
+    pub date: u64,
+    /// The entrants ordered by their placement.
+    pub entrants: Vec<EntrantRecord>,
+    /// The teams ordered by their placement, if it was a team race.
+    #[serde(default)]
+    pub teams: Vec<TeamRecord>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EntrantRecord {
+    pub id: u64,
+    pub name: String,
+    /// Entrants that didn't finish have no placement. In a team race, the
+    /// entrants share their team's placement.
+    pub place: Option<usize>,
+    pub time: Option<Duration>,
+    pub forfeited: bool,
+    #[serde(default)]
+    pub team: Option<String>,
+    /// When the entrant split, relative to their start.
+    #[serde(default)]
+    pub splits: Vec<Duration>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TeamRecord {
+    pub name: String,
+    pub place: Option<usize>,
+    pub time: Option<Duration>,
+}
+
+/// The seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// The seconds since the Unix epoch at the time the race started, given how
+/// long ago that was.
+pub fn date(elapsed: Duration) -> u64 {
+    now().saturating_sub(elapsed.as_secs())
+}
+
+/// Formats the date as `YYYY-MM-DD` in UTC.
+pub fn format_date(date: u64) -> String {
+    // Converts days since the epoch to the civil date, following Howard
+    // Hinnant's `civil_from_days`.
+    let days = (date / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 -
+                       day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats the date as `YYYY-MM-DD HH:MM UTC`.
+pub fn format_date_time(date: u64) -> String {
+    format!("{} {:02}:{:02} UTC",
+            format_date(date),
+            date / 3600 % 24,
+            date / 60 % 60)
+}
+
+impl RaceRecord {
+    pub fn title(&self) -> String {
+        format!("Race #{}: {} - {}", self.id, self.game, self.category)
+    }
+
+    /// The name and time of the entrant or team that won.
+    pub fn winner(&self) -> Option<(&str, Option<Duration>)> {
+        if self.teams.is_empty() {
+            self.entrants
+                .iter()
+                .find(|e| e.place == Some(1))
+                .map(|e| (e.name.as_str(), e.time))
+        } else {
+            self.teams
+                .iter()
+                .find(|t| t.place == Some(1))
+                .map(|t| (t.name.as_str(), t.time))
+        }
+    }
+
+    /// One line per entrant with their placement and time. The entrants of
+    /// a team race are listed below their team.
+    pub fn results(&self) -> String {
+        let status = |time: Option<Duration>, forfeited| match time {
+            Some(time) => format_time(time),
+            None if forfeited => String::from("Forfeited"),
+            None => String::from("Did not finish"),
+        };
+        let place = |place: Option<usize>| {
+            place.map_or_else(|| String::from("-"), |p| p.to_string())
+        };
+
+        let mut lines = Vec::new();
+        if self.teams.is_empty() {
+            for entrant in &self.entrants {
+                lines.push(format!("{}. {} ({})",
+                                   place(entrant.place),
+                                   entrant.name,
+                                   status(entrant.time, entrant.forfeited)));
+            }
+        }
+        for team in &self.teams {
+            lines.push(format!("{}. {} ({})",
+                               place(team.place),
+                               team.name,
+                               status(team.time, false)));
+            for entrant in self.entrants
+                    .iter()
+                    .filter(|e| e.team.as_ref() == Some(&team.name)) {
+                lines.push(format!("  {} ({})",
+                                   entrant.name,
+                                   status(entrant.time, entrant.forfeited)));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn load<P: AsRef<Path>>(storage_path: P) -> Vec<RaceRecord> {
+    storage::load_json(storage_path, FILE_NAME)
+}
+
+pub fn save<P: AsRef<Path>>(storage_path: P, races: &[RaceRecord]) -> Result<(), Error> {
+    storage::save_json(storage_path, FILE_NAME, races)
+}
+
+#[test]
+fn format_dates() {
+    assert_eq!(format_date(0), "1970-01-01");
+    assert_eq!(format_date(951_782_400), "2000-02-29");
+    assert_eq!(format_date(1_497_052_800), "2017-06-10");
+    assert_eq!(format_date_time(1_497_105_900), "2017-06-10 14:45 UTC");
+}
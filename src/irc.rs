@@ -0,0 +1,445 @@
+use std::ascii::AsciiExt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use dotenv::var;
+use parking_lot::Mutex;
+use commands::{self, Invoker, Reply};
+use error::Error;
+use {LSState, Recipient, supervisor};
+
+/// Leaves enough room in the 512 byte IRC line for the command and target.
+const MAX_MESSAGE_LENGTH: usize = 400;
+/// Seconds between posts of the standings of a running race.
+const STATUS_INTERVAL: u64 = 60;
+
+pub const SERVICE: &'static str = "irc";
+/// Twitch authenticates the nicks in its chat, unlike other IRC networks, so
+/// only Twitch identities can be linked to accounts.
+pub const TWITCH_SERVICE: &'static str = "twitch";
+const CHANNEL_SERVICE: &'static str = "irc-channel";
+
+struct Settings {
+    server: String,
+    nick: String,
+    password: Option<String>,
+    channels: Vec<String>,
+}
+
+impl Settings {
+    fn from_env() -> Option<Self> {
+        let server = match var("IRC_SERVER") {
+            Ok(server) => server,
+            Err(_) => return None,
+        };
+        Some(Settings {
+                 server: server,
+                 nick: var("IRC_NICK").unwrap_or_else(|_| String::from("livesplit")),
+                 password: var("IRC_PASS").ok(),
+                 channels: var("IRC_CHANNELS")
+                     .unwrap_or_default()
+                     .split(',')
+                     .map(|c| c.trim())
+                     .filter(|c| !c.is_empty())
+                     .map(|c| if c.starts_with('#') {
+                              c.to_owned()
+                          } else {
+                              format!("#{}", c)
+                          })
+                     .collect(),
+             })
+    }
+
+    /// The service the nicks on the server belong to.
+    fn service(&self) -> &'static str {
+        let host = self.server.split(':').next().unwrap_or_default().to_lowercase();
+        if host == "twitch.tv" || host.ends_with(".twitch.tv") {
+            TWITCH_SERVICE
+        } else {
+            SERVICE
+        }
+    }
+}
+
+/// A message received from the server.
+#[derive(Debug, PartialEq)]
+enum Message<'a> {
+    Ping(&'a str),
+    Privmsg {
+        nick: &'a str,
+        target: &'a str,
+        text: &'a str,
+    },
+    Other,
+}
+
+fn parse_message(line: &str) -> Message {
+    let mut line = line.trim_right_matches(|c| c == '\r' || c == '\n');
+
+    // Twitch prepends IRCv3 tags if they are requested.
+    if line.starts_with('@') {
+        line = match line.find(' ') {
+            Some(index) => &line[index + 1..],
+            None => return Message::Other,
+        };
+    }
+
+    let mut prefix = "";
+    if line.starts_with(':') {
+        match line.find(' ') {
+            Some(index) => {
+                prefix = &line[1..index];
+                line = &line[index + 1..];
+            }
+            None => return Message::Other,
+        }
+    }
+
+    if line.starts_with("PING ") {
+        return Message::Ping(line[5..].trim_left_matches(':'));
+    }
+
+    if line.starts_with("PRIVMSG ") {
+        let rest = &line[8..];
+        if let Some(index) = rest.find(" :") {
+            let nick = prefix.split('!').next().unwrap_or("");
+            return Message::Privmsg {
+                       nick: nick,
+                       target: &rest[..index],
+                       text: &rest[index + 2..],
+                   };
+        }
+    }
+
+    Message::Other
+}
+
+/// Flattens a reply into lines that fit into a single IRC message each.
+fn to_lines(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for part in text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        let part = part.replace("**", "");
+        if !line.is_empty() && line.len() + part.len() + 3 > MAX_MESSAGE_LENGTH {
+            lines.push(line);
+            line = String::new();
+        }
+        if !line.is_empty() {
+            line.push_str(" | ");
+        }
+        line.push_str(&part);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+struct Connection {
+    output: Mutex<TcpStream>,
+    service: &'static str,
+    last_status: Mutex<Option<Instant>>,
+}
+
+impl Connection {
+    fn send_raw(&self, line: &str) -> Result<(), Error> {
+        let mut output = self.output.lock();
+        write!(output, "{}\r\n", line)
+            .and_then(|_| output.flush())
+            .map_err(|e| Error::Transport(format!("Couldn't send to IRC: {}", e)))
+    }
+
+    fn say(&self, target: &str, text: &str) -> Result<(), Error> {
+        for line in to_lines(text) {
+            self.send_raw(&format!("PRIVMSG {} :{}", target, line))?;
+        }
+        Ok(())
+    }
+
+    /// Sends a private message to the user. Twitch doesn't deliver those,
+    /// it only knows whispers, which are sent with a chat command.
+    fn whisper(&self, nick: &str, text: &str) -> Result<(), Error> {
+        if self.service != TWITCH_SERVICE {
+            return self.say(nick, text);
+        }
+        for line in to_lines(text) {
+            self.send_raw(&format!("PRIVMSG #jtv :/w {} {}", nick, line))?;
+        }
+        Ok(())
+    }
+
+    /// Whether the standings of the race are due to be posted again. Chat
+    /// can't edit messages, so posting them on every split would flood it.
+    fn status_due(&self) -> bool {
+        let mut last_status = self.last_status.lock();
+        match *last_status {
+            Some(last) if last.elapsed() < Duration::from_secs(STATUS_INTERVAL) => false,
+            _ => {
+                *last_status = Some(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+fn send_replies(state: &LSState,
+                connection: &Connection,
+                invoker: &Invoker,
+                target: &str,
+                replies: Vec<Reply>)
+                -> Result<(), Error> {
+    for reply in commands::countdowns_last(replies) {
+        match reply {
+            Reply::Text(text) => connection.say(target, &text)?,
+            Reply::Embed(embed) => connection.say(target, &embed.to_text())?,
+            // The results get posted once the race is over anyway.
+            Reply::RaceStatus { embed, finished, .. } => {
+                if !finished && connection.status_due() {
+                    connection.say(target, &embed.to_text())?;
+                }
+            }
+            // Chat can't show images, the text links to the board instead.
+            Reply::File { text, .. } => connection.say(target, &text)?,
+            Reply::Direct(user, text) => {
+                match state.identity_name(connection.service, user) {
+                    Some(nick) => connection.whisper(&nick, &text)?,
+                    // The user may be known to another frontend instead.
+                    None => state.announce_direct(user, &text),
+                }
+            }
+            Reply::Countdown(seconds) => {
+                connection
+                    .say(target,
+                         &format!("All entrants are ready! Starting in {} seconds.",
+                                  seconds))?;
+                thread::sleep(Duration::from_secs(seconds));
+                connection.say(target, "Go!")?;
+                let replies = commands::start_race(invoker, state)?;
+                send_replies(state, connection, invoker, target, replies)?;
+            }
+            Reply::AsyncCountdown { race, seconds } => {
+                connection
+                    .say(target,
+                         &format!("Get ready for async race #{}! Starting in {} seconds.",
+                                  race,
+                                  seconds))?;
+                thread::sleep(Duration::from_secs(seconds));
+                connection.say(target, "Go!")?;
+                let replies = commands::start_async_run(invoker, race, state)?;
+                send_replies(state, connection, invoker, target, replies)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle(state: &LSState, connection: &Connection, nick: &str, target: &str, text: &str) {
+    // Messages sent to a channel are answered there, private messages in
+    // private.
+    let reply_target = if target.starts_with('#') {
+        target
+    } else {
+        nick
+    };
+    let channel = state.identity(CHANNEL_SERVICE, reply_target);
+
+    let (command, params) = match commands::parse(&state.prefix(channel), text) {
+        Some(command) => command,
+        None => return,
+    };
+
+    let invoker = Invoker {
+        id: state.identity(connection.service, nick),
+        name: nick.to_owned(),
+        channel: channel,
+        guild: channel,
+        // The broadcaster owns the channel named after them. Anyone can use
+        // any nick on other networks, so nobody owns a channel there.
+        manage_guild: connection.service == TWITCH_SERVICE &&
+                      reply_target
+                          .trim_left_matches('#')
+                          .eq_ignore_ascii_case(nick),
+        roles: Vec::new(),
+    };
+
+    let result = match commands::execute(state, &invoker, &command, params) {
+        Some(result) => {
+            result.and_then(|replies| {
+                                send_replies(state, connection, &invoker, reply_target, replies)
+                            })
+        }
+        None => return,
+    };
+
+    if let Err(e) = result {
+        if !e.is_user_error() {
+            error!("Command `{}` by {} failed: {}", command, invoker.name, e);
+        }
+        if let Err(e) = connection.say(reply_target, &e.user_message()) {
+            error!("Couldn't report the error to {}: {}", invoker.name, e);
+        }
+    }
+}
+
+fn run(state: &Arc<LSState>,
+       settings: &Settings,
+       current: &Mutex<Option<Arc<Connection>>>)
+       -> io::Result<()> {
+    let stream = TcpStream::connect(settings.server.as_str())?;
+    let connection = Arc::new(Connection {
+                                  output: Mutex::new(stream.try_clone()?),
+                                  service: settings.service(),
+                                  last_status: Mutex::new(None),
+                              });
+    *current.lock() = Some(connection.clone());
+
+    let register = || -> Result<(), Error> {
+        if let Some(ref password) = settings.password {
+            connection.send_raw(&format!("PASS {}", password))?;
+        }
+        connection.send_raw(&format!("NICK {}", settings.nick))?;
+        connection
+            .send_raw(&format!("USER {} 0 * :LiveSplit", settings.nick))?;
+        for channel in &settings.channels {
+            connection.send_raw(&format!("JOIN {}", channel))?;
+        }
+        Ok(())
+    };
+    register()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    info!("Connected to IRC at {}", settings.server);
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        match parse_message(&line) {
+            Message::Ping(token) => {
+                if let Err(e) = connection.send_raw(&format!("PONG :{}", token)) {
+                    return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+                }
+            }
+            Message::Privmsg { nick, target, text } => {
+                // Commands like `!ready` may count down, so they shouldn't
+                // block answering the server's pings.
+                let (state, connection) = (state.clone(), connection.clone());
+                let (nick, target, text) = (nick.to_owned(), target.to_owned(), text.to_owned());
+                thread::spawn(move || handle(&state, &connection, &nick, &target, &text));
+            }
+            Message::Other => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Connects to the IRC server configured by `IRC_SERVER`, like
+/// `irc.chat.twitch.tv:6667`, and answers the commands in the channels listed
+/// in `IRC_CHANNELS`. Reconnects with an increasing delay whenever the
+/// connection is lost. Does nothing if no server is configured.
+pub fn start(state: Arc<LSState>) -> Option<thread::JoinHandle<()>> {
+    let settings = match Settings::from_env() {
+        Some(settings) => settings,
+        None => return None,
+    };
+
+    let current = Arc::new(Mutex::new(None::<Arc<Connection>>));
+    let announcer_connection = current.clone();
+    state.add_announcer(Box::new(move |state, recipient, text| {
+        let connection = match announcer_connection.lock().clone() {
+            Some(connection) => connection,
+            None => return false,
+        };
+        match recipient {
+            Recipient::Channel(channel) => {
+                state
+                    .identity_name(CHANNEL_SERVICE, channel)
+                    .map_or(false, |target| connection.say(&target, text).is_ok())
+            }
+            Recipient::User(user) => {
+                state
+                    .identity_name(connection.service, user)
+                    .map_or(false, |nick| connection.whisper(&nick, text).is_ok())
+            }
+        }
+    }));
+
+    Some(thread::spawn(move || {
+        supervisor::supervise("IRC connection",
+                              || match run(&state, &settings, &current) {
+                                  Ok(()) => warn!("The IRC server closed the connection"),
+                                  Err(e) => error!("The IRC connection failed: {}", e),
+                              })
+    }))
+}
+
+#[test]
+fn parse_messages() {
+    assert_eq!(parse_message("PING :tmi.twitch.tv\r\n"),
+               Message::Ping("tmi.twitch.tv"));
+    assert_eq!(parse_message(":runner!runner@runner.tmi.twitch.tv PRIVMSG #race :!timer"),
+               Message::Privmsg {
+                   nick: "runner",
+                   target: "#race",
+                   text: "!timer",
+               });
+    assert_eq!(parse_message("@badges=;color= :a!a@a PRIVMSG #race :!mark 1 2"),
+               Message::Privmsg {
+                   nick: "a",
+                   target: "#race",
+                   text: "!mark 1 2",
+               });
+    assert_eq!(parse_message(":tmi.twitch.tv 001 livesplit :Welcome"),
+               Message::Other);
+}
+
+#[test]
+fn flatten_replies() {
+    assert_eq!(to_lines("**Entrants**\n\nRunner 1  Ready\n"),
+               vec![String::from("Entrants | Runner 1  Ready")]);
+
+    let long = (0..100).map(|i| format!("Line {}", i)).collect::<Vec<_>>().join("\n");
+    let lines = to_lines(&long);
+    assert!(lines.len() > 1);
+    assert!(lines.iter().all(|l| l.len() <= MAX_MESSAGE_LENGTH));
+}
+
+#[test]
+fn chat_users_get_their_own_ids() {
+    let storage_path = "target/test-storage/chat-users";
+    let _ = ::std::fs::remove_dir_all(storage_path);
+    let new_state = || {
+        LSState::new(::config::Config {
+                         storage_path: String::from(storage_path),
+                         ..::config::Config::default()
+                     })
+    };
+    let state = new_state();
+    let runner = state.identity(SERVICE, "Runner");
+    assert_eq!(state.identity(SERVICE, "runner"), runner);
+    let other = state.identity(SERVICE, "other");
+    assert!(other != runner);
+    assert!(state.identity(CHANNEL_SERVICE, "runner") != runner);
+    assert_eq!(state.identity_name(SERVICE, runner), Some(String::from("runner")));
+
+    // The IDs stay the same after a restart.
+    let state = new_state();
+    assert_eq!(state.identity(SERVICE, "other"), other);
+    assert_eq!(state.identity(SERVICE, "runner"), runner);
+    assert!(state.identity(SERVICE, "newcomer") > other);
+}
+
+#[test]
+fn only_twitch_authenticates_nicks() {
+    let settings = |server: &str| {
+        Settings {
+            server: server.to_owned(),
+            nick: String::from("livesplit"),
+            password: None,
+            channels: Vec::new(),
+        }
+    };
+    assert_eq!(settings("irc.chat.twitch.tv:6667").service(), TWITCH_SERVICE);
+    assert_eq!(settings("irc.libera.chat:6667").service(), SERVICE);
+    assert_eq!(settings("twitch.tv.example.com:6667").service(), SERVICE);
+}
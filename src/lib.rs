@@ -0,0 +1,327 @@
+#![feature(plugin)]
+#![plugin(rocket_codegen)]
+
+extern crate dotenv;
+extern crate rocket;
+extern crate rocket_contrib;
+extern crate livesplit_core;
+extern crate parking_lot;
+#[macro_use]
+extern crate serde_derive;
+extern crate serenity;
+extern crate hyper;
+extern crate hyper_rustls;
+extern crate chashmap;
+#[macro_use]
+extern crate log;
+extern crate speedrun_bingo;
+extern crate rand;
+extern crate image;
+extern crate toml;
+extern crate serde;
+extern crate serde_json;
+
+use chashmap::{CHashMap, WriteGuard};
+use livesplit_core::Timer;
+use livesplit_core::component::{title, splits, timer, previous_segment, sum_of_best,
+                                possible_time_save};
+use std::collections::HashMap;
+use parking_lot::{Mutex, RwLock};
+use race::{AsyncRace, Race};
+use bingo::{RenderOptions, Templates};
+use config::Config;
+use settings::GuildSettings;
+use history::RaceRecord;
+use rating::Ratings;
+
+mod bingo;
+pub mod commands;
+pub mod config;
+pub mod discord;
+pub mod error;
+mod history;
+pub mod irc;
+mod race;
+mod rating;
+pub mod rest_api;
+mod settings;
+mod storage;
+mod supervisor;
+pub mod tcp_server;
+
+#[derive(Serialize)]
+pub struct Layout {
+    title: title::State,
+    splits: splits::State,
+    timer: timer::State,
+    previous_segment: previous_segment::State,
+    sum_of_best: sum_of_best::State,
+    possible_time_save: possible_time_save::State,
+}
+
+pub struct LSState {
+    users: CHashMap<u64, User>,
+    race: RwLock<Race>,
+    /// The async races that are still open.
+    async_races: RwLock<Vec<AsyncRace>>,
+    config: Config,
+    bingo_templates: Templates,
+    guild_settings: RwLock<HashMap<u64, GuildSettings>>,
+    tokens: RwLock<HashMap<String, u64>>,
+    /// The IDs of the users and channels of other frontends, keyed by the
+    /// service and the lowercase name.
+    identities: RwLock<HashMap<(String, String), u64>>,
+    next_identity: Mutex<u64>,
+    /// Identities waiting for their verification code, keyed by the code.
+    pending_links: RwLock<HashMap<String, PendingLink>>,
+    announcers: RwLock<Vec<Box<Announcer>>>,
+    next_race_id: Mutex<u64>,
+    history: RwLock<Vec<RaceRecord>>,
+    ratings: RwLock<Ratings>,
+}
+
+/// Where a message outside of a command's replies goes.
+#[derive(Clone, Copy, Debug)]
+pub enum Recipient {
+    Channel(u64),
+    /// A direct message to the user.
+    User(u64),
+}
+
+/// Posts a message outside of a command's replies. Returns whether the
+/// frontend knows the recipient.
+pub type Announcer = Fn(&LSState, Recipient, &str) -> bool + Send + Sync;
+
+/// A user or channel of another frontend, as it is stored.
+#[derive(Serialize, Deserialize)]
+struct Identity {
+    service: String,
+    name: String,
+    id: u64,
+}
+
+const IDENTITIES_FILE: &'static str = "identities.json";
+
+struct PendingLink {
+    user: u64,
+    service: &'static str,
+    name: String,
+}
+
+/// IDs of users and channels of frontends other than Discord are allocated
+/// from here on, so they never collide with Discord's snowflakes.
+const FIRST_IDENTITY: u64 = 1 << 63;
+
+pub struct User {
+    name: String,
+    components: Components,
+    timer: Timer,
+}
+
+pub struct Components {
+    title: title::Component,
+    splits: splits::Component,
+    timer: timer::Component,
+    previous_segment: previous_segment::Component,
+    sum_of_best: sum_of_best::Component,
+    possible_time_save: possible_time_save::Component,
+}
+
+impl LSState {
+    pub fn new(config: Config) -> Self {
+        let bingo_templates = match config.bingo_templates {
+            Some(ref directory) => Templates::load(directory),
+            None => Templates::default(),
+        };
+        let guild_settings = settings::load(&config.storage_path);
+        let history = history::load(&config.storage_path);
+        let next_race_id = history.iter().map(|race| race.id).max().unwrap_or(0) + 1;
+        let identities: Vec<Identity> = storage::load_json(&config.storage_path, IDENTITIES_FILE);
+        let next_identity = identities
+            .iter()
+            .map(|identity| identity.id)
+            .filter(|&id| id >= FIRST_IDENTITY)
+            .max()
+            .map_or(FIRST_IDENTITY, |id| id + 1);
+        let identities = identities
+            .into_iter()
+            .map(|identity| ((identity.service, identity.name), identity.id))
+            .collect();
+        LSState {
+            users: CHashMap::new(),
+            race: RwLock::new(Race::NoRace),
+            async_races: RwLock::new(Vec::new()),
+            config: config,
+            bingo_templates: bingo_templates,
+            guild_settings: RwLock::new(guild_settings),
+            tokens: RwLock::new(HashMap::new()),
+            identities: RwLock::new(identities),
+            next_identity: Mutex::new(next_identity),
+            pending_links: RwLock::new(HashMap::new()),
+            announcers: RwLock::new(Vec::new()),
+            next_race_id: Mutex::new(next_race_id),
+            ratings: RwLock::new(Ratings::new(&history)),
+            history: RwLock::new(history),
+        }
+    }
+
+    fn user<S>(&self, id: u64, name: S) -> WriteGuard<u64, User>
+        where S: AsRef<str>
+    {
+        loop {
+            if let Some(user) = self.users.get_mut(&id) {
+                return user;
+            }
+            info!("New User {}", name.as_ref());
+            let timer = Timer::new(self.config.default_run.run());
+            self.users
+                .insert(id,
+                        User {
+                            name: name.as_ref().to_owned(),
+                            components: Components {
+                                title: title::Component::new(),
+                                splits: splits::Component::new(),
+                                timer: timer::Component::new(),
+                                previous_segment: previous_segment::Component::new(),
+                                sum_of_best: sum_of_best::Component::new(),
+                                possible_time_save: possible_time_save::Component::new(),
+                            },
+                            timer: timer,
+                        });
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn guild_settings(&self, guild: u64) -> GuildSettings {
+        self.guild_settings
+            .read()
+            .get(&guild)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The prefix commands start with in the guild.
+    pub fn prefix(&self, guild: u64) -> String {
+        self.guild_settings(guild)
+            .prefix
+            .unwrap_or_else(|| self.config.prefix.clone())
+    }
+
+    fn countdown(&self, guild: u64) -> u64 {
+        self.guild_settings(guild)
+            .countdown
+            .unwrap_or(self.config.countdown)
+    }
+
+    fn next_race_id(&self) -> u64 {
+        let mut next_race_id = self.next_race_id.lock();
+        *next_race_id += 1;
+        *next_race_id - 1
+    }
+
+    pub fn add_announcer(&self, announcer: Box<Announcer>) {
+        self.announcers.write().push(announcer);
+    }
+
+    /// Posts the message to the channel with the frontend it belongs to.
+    pub fn announce(&self, channel: u64, text: &str) {
+        self.send(Recipient::Channel(channel), text);
+    }
+
+    /// Sends the message to the user with the first frontend that knows
+    /// them.
+    pub fn announce_direct(&self, user: u64, text: &str) {
+        self.send(Recipient::User(user), text);
+    }
+
+    fn send(&self, recipient: Recipient, text: &str) {
+        if !self.announcers
+                .read()
+                .iter()
+                .any(|announce| announce(self, recipient, text)) {
+            warn!("Couldn't announce `{}` to {:?}", text, recipient);
+        }
+    }
+
+    /// The ID of a user or channel of another frontend, like a Twitch chat
+    /// user. A new ID is allocated the first time the name is seen.
+    fn identity(&self, service: &'static str, name: &str) -> u64 {
+        let key = (service.to_owned(), name.to_lowercase());
+        if let Some(&id) = self.identities.read().get(&key) {
+            return id;
+        }
+        let mut identities = self.identities.write();
+        if let Some(&id) = identities.get(&key) {
+            return id;
+        }
+        let id = {
+            let mut next_identity = self.next_identity.lock();
+            *next_identity += 1;
+            *next_identity - 1
+        };
+        identities.insert(key, id);
+        self.save_identities(&identities);
+        id
+    }
+
+    /// Makes the identity refer to the user from now on, so both share the
+    /// same timer. Returns the ID the identity referred to before.
+    fn link_identity(&self, service: &'static str, name: &str, user: u64) -> Option<u64> {
+        let mut identities = self.identities.write();
+        let previous = identities.insert((service.to_owned(), name.to_lowercase()), user);
+        self.save_identities(&identities);
+        previous
+    }
+
+    /// The IDs end up in the race history and the guild settings, so they
+    /// need to refer to the same users and channels after a restart.
+    fn save_identities(&self, identities: &HashMap<(String, String), u64>) {
+        let mut records = identities
+            .iter()
+            .map(|(&(ref service, ref name), &id)| {
+                     Identity {
+                         service: service.clone(),
+                         name: name.clone(),
+                         id: id,
+                     }
+                 })
+            .collect::<Vec<_>>();
+        records.sort_by_key(|identity| identity.id);
+        if let Err(e) = storage::save_json(&self.config.storage_path, IDENTITIES_FILE, &records) {
+            error!("Couldn't save the identities: {}", e);
+        }
+    }
+
+    /// The name the ID was allocated for by `identity`.
+    fn identity_name(&self, service: &'static str, id: u64) -> Option<String> {
+        self.identities
+            .read()
+            .iter()
+            .find(|&(&(ref s, _), &i)| s == service && i == id)
+            .map(|(&(_, ref name), _)| name.clone())
+    }
+
+    /// The render options a guild uses for a bingo template, falling back to
+    /// the guild's default style.
+    fn bingo_style(&self, guild: u64, template: &str) -> RenderOptions {
+        self.guild_settings(guild).bingo_style(Some(template))
+    }
+}
+
+impl User {
+    fn eval_layout(&mut self) -> Layout {
+        Layout {
+            title: self.components.title.state(&mut self.timer),
+            splits: self.components.splits.state(&mut self.timer),
+            timer: self.components.timer.state(&mut self.timer),
+            previous_segment: self.components.previous_segment.state(&mut self.timer),
+            sum_of_best: self.components.sum_of_best.state(&mut self.timer),
+            possible_time_save: self.components
+                .possible_time_save
+                .state(&mut self.timer),
+        }
+    }
+}
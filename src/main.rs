@@ -1,125 +1,66 @@
-#![feature(plugin)]
-#![plugin(rocket_codegen)]
-
-extern crate dotenv;
-extern crate rocket;
-extern crate rocket_contrib;
-extern crate livesplit_core;
-extern crate parking_lot;
-#[macro_use]
-extern crate serde_derive;
-extern crate serenity;
-extern crate hyper;
-extern crate hyper_rustls;
-extern crate chashmap;
+extern crate env_logger;
+extern crate livesplit_server;
 #[macro_use]
 extern crate log;
-extern crate speedrun_bingo;
-extern crate rand;
-extern crate image;
 
-use chashmap::{CHashMap, WriteGuard};
-use livesplit_core::{Timer, Run, Segment};
-use livesplit_core::component::{title, splits, timer, previous_segment, sum_of_best,
-                                possible_time_save};
+use std::env;
+use std::io::{self, Write};
+use std::process;
 use std::sync::Arc;
-use parking_lot::RwLock;
-
-mod discord;
-mod rest_api;
-
-#[derive(Serialize)]
-pub struct Layout {
-    title: title::State,
-    splits: splits::State,
-    timer: timer::State,
-    previous_segment: previous_segment::State,
-    sum_of_best: sum_of_best::State,
-    possible_time_save: possible_time_save::State,
-}
+use env_logger::LogBuilder;
+use log::LogLevelFilter;
+use livesplit_server::{LSState, commands, discord, irc, rest_api, tcp_server};
+use livesplit_server::config::Config;
 
-pub struct LSState {
-    users: CHashMap<u64, User>,
-    race: RwLock<Race>,
+/// Logs everything from `info` on to stderr, unless `RUST_LOG` says
+/// otherwise.
+fn init_logger() {
+    let mut logger = LogBuilder::new();
+    logger.filter(None, LogLevelFilter::Info);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        logger.parse(&filters);
+    }
+    if let Err(e) = logger.init() {
+        let _ = writeln!(io::stderr(), "Couldn't set up logging: {}", e);
+    }
 }
 
-pub enum Race {
-    NoRace,
-    Setup(Vec<(u64, bool)>),
-    InProgress(Vec<u64>),
-}
+fn main() {
+    init_logger();
 
-pub struct User {
-    name: String,
-    components: Components,
-    timer: Timer,
-}
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let state = Arc::new(LSState::new(config));
+    let modules = &state.config().modules;
 
-pub struct Components {
-    title: title::Component,
-    splits: splits::Component,
-    timer: timer::Component,
-    previous_segment: previous_segment::Component,
-    sum_of_best: sum_of_best::Component,
-    possible_time_save: possible_time_save::Component,
-}
+    commands::watch_races(state.clone());
 
-impl LSState {
-    fn user<S>(&self, id: u64, name: S) -> WriteGuard<u64, User>
-        where S: AsRef<str>
-    {
-        loop {
-            if let Some(user) = self.users.get_mut(&id) {
-                return user;
-            }
-            info!("New User {}", name.as_ref());
-            let mut run = Run::new();
-            run.push_segment(Segment::new("First"));
-            run.push_segment(Segment::new("Second"));
-            run.push_segment(Segment::new("Third"));
-            run.push_segment(Segment::new("End"));
-            run.set_game_name("Wind Waker");
-            run.set_category_name("Any%");
-            let timer = Timer::new(run);
-            self.users
-                .insert(id,
-                        User {
-                            name: name.as_ref().to_owned(),
-                            components: Components {
-                                title: title::Component::new(),
-                                splits: splits::Component::new(),
-                                timer: timer::Component::new(),
-                                previous_segment: previous_segment::Component::new(),
-                                sum_of_best: sum_of_best::Component::new(),
-                                possible_time_save: possible_time_save::Component::new(),
-                            },
-                            timer: timer,
-                        });
+    let mut services = Vec::new();
+    if modules.rest_api {
+        services.push(("REST API", rest_api::start(state.clone())));
+    }
+    if modules.livesplit_server {
+        services.push(("LiveSplit Server", tcp_server::start(state.clone())));
+    }
+    if modules.irc {
+        if let Some(irc) = irc::start(state.clone()) {
+            services.push(("IRC client", irc));
         }
     }
-}
+    if modules.discord {
+        discord::start(state.clone());
+    }
 
-impl User {
-    fn eval_layout(&mut self) -> Layout {
-        Layout {
-            title: self.components.title.state(&mut self.timer),
-            splits: self.components.splits.state(&mut self.timer),
-            timer: self.components.timer.state(&mut self.timer),
-            previous_segment: self.components.previous_segment.state(&mut self.timer),
-            sum_of_best: self.components.sum_of_best.state(&mut self.timer),
-            possible_time_save: self.components
-                .possible_time_save
-                .state(&mut self.timer),
+    // Keep serving the other frontends even if the Discord client couldn't
+    // start.
+    for (name, service) in services {
+        if service.join().is_err() {
+            error!("The {} crashed", name);
         }
     }
 }
-
-fn main() {
-    let state = Arc::new(LSState {
-                             users: CHashMap::new(),
-                             race: RwLock::new(Race::NoRace),
-                         });
-
-    rest_api::start(state.clone());
-    discord::start(state);
-}
@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use bingo::{BingoGame, Variant};
+
+pub enum Race {
+    NoRace,
+    Setup(RaceSetup),
+    /// All entrants are ready and the countdown is running.
+    Countdown(RaceSetup),
+    InProgress(RaceInProgress),
+}
+
+/// How the times of a team's runners make up the team's time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeamMode {
+    /// The team's best runner counts.
+    Best,
+    /// Every runner counts.
+    Sum,
+    /// The runners take turns, each starting once the previous one is done.
+    Relay,
+}
+
+pub struct RaceSetup {
+    pub id: u64,
+    /// The user that created the race, who gets to organize it.
+    pub creator: u64,
+    pub entrants: Vec<(u64, bool)>,
+    pub team_mode: Option<TeamMode>,
+    /// The team each entrant joined in a team race.
+    pub teams: HashMap<u64, String>,
+    /// What the race is about, if the creator said so.
+    pub game: Option<String>,
+    pub category: Option<String>,
+    pub goal: Option<String>,
+    pub bingo: Option<Variant>,
+    /// Whether new entrants are turned away.
+    pub closed: bool,
+    /// The channel the race got created in, where it is announced.
+    pub channel: u64,
+    pub guild: u64,
+    pub created: Instant,
+    /// When a scheduled race starts, in seconds since the Unix epoch. The
+    /// countdown doesn't start before then.
+    pub start_at: Option<u64>,
+    /// Whether the entrants were reminded of the scheduled race.
+    pub reminded: bool,
+    /// The users that get direct messages about how the race goes.
+    pub watchers: Vec<u64>,
+}
+
+pub struct RaceInProgress {
+    pub id: u64,
+    pub game: String,
+    pub category: String,
+    pub goal: Option<String>,
+    pub team_mode: Option<TeamMode>,
+    pub creator: u64,
+    pub channel: u64,
+    pub guild: u64,
+    pub start: Instant,
+    pub entrants: Vec<Entrant>,
+    pub bingo: Option<BingoGame>,
+    pub watchers: Vec<u64>,
+}
+
+/// A race that stays open for a while, where every entrant starts their own
+/// run whenever they like. The results are revealed once it closes.
+pub struct AsyncRace {
+    pub race: RaceInProgress,
+    /// When the race closes, in seconds since the Unix epoch.
+    pub closes_at: u64,
+}
+
+pub struct Entrant {
+    pub id: u64,
+    pub team: Option<String>,
+    /// When the entrant started, relative to the race's start. Only relay
+    /// runners start late.
+    pub start: Duration,
+    /// The entrant's own time, from their start to their finish.
+    pub finish_time: Option<Duration>,
+    pub forfeited: bool,
+    /// Whether the relay runner still waits for their teammate to hand off,
+    /// or the async runner for their countdown to end.
+    pub waiting: bool,
+    /// When the entrant split during the race, relative to its start.
+    pub splits: Vec<Duration>,
+}
+
+impl Entrant {
+    pub fn is_running(&self) -> bool {
+        self.finish_time.is_none() && !self.forfeited && !self.waiting
+    }
+}
+
+impl Race {
+    /// The user that created the race, if there is one.
+    pub fn creator(&self) -> Option<u64> {
+        match *self {
+            Race::NoRace => None,
+            Race::Setup(ref setup) |
+            Race::Countdown(ref setup) => Some(setup.creator),
+            Race::InProgress(ref race) => Some(race.creator),
+        }
+    }
+
+    /// The guild the race was created in, if there is one.
+    pub fn guild(&self) -> Option<u64> {
+        match *self {
+            Race::NoRace => None,
+            Race::Setup(ref setup) |
+            Race::Countdown(ref setup) => Some(setup.guild),
+            Race::InProgress(ref race) => Some(race.guild),
+        }
+    }
+
+    /// The users watching the race, if there is one.
+    pub fn watchers_mut(&mut self) -> Option<&mut Vec<u64>> {
+        match *self {
+            Race::NoRace => None,
+            Race::Setup(ref mut setup) |
+            Race::Countdown(ref mut setup) => Some(&mut setup.watchers),
+            Race::InProgress(ref mut race) => Some(&mut race.watchers),
+        }
+    }
+}
+
+impl RaceSetup {
+    /// Describes the game, category and goal, if any of them are known.
+    pub fn title(&self) -> Option<String> {
+        let mut title = match (self.game.as_ref(), self.category.as_ref()) {
+            (Some(game), Some(category)) => format!("{} - {}", game, category),
+            (Some(name), None) | (None, Some(name)) => name.clone(),
+            (None, None) => String::new(),
+        };
+        if let Some(ref goal) = self.goal {
+            if !title.is_empty() {
+                title.push_str(": ");
+            }
+            title.push_str(goal);
+        }
+        if title.is_empty() { None } else { Some(title) }
+    }
+}
+
+impl RaceInProgress {
+    pub fn new(setup: &RaceSetup,
+               game: String,
+               category: String,
+               bingo: Option<BingoGame>)
+               -> RaceInProgress {
+        RaceInProgress {
+            id: setup.id,
+            game: game,
+            category: category,
+            goal: setup.goal.clone(),
+            team_mode: setup.team_mode,
+            creator: setup.creator,
+            channel: setup.channel,
+            guild: setup.guild,
+            start: Instant::now(),
+            entrants: setup
+                .entrants
+                .iter()
+                .enumerate()
+                .map(|(index, &(id, _))| {
+                    let team = setup.teams.get(&id).cloned();
+                    // Relay runners wait for the teammates that entered
+                    // before them.
+                    let waiting = setup.team_mode == Some(TeamMode::Relay) &&
+                                  setup.entrants[..index]
+                                      .iter()
+                                      .any(|&(other, _)| setup.teams.get(&other) == team.as_ref());
+                    Entrant {
+                        id: id,
+                        team: team,
+                        start: Duration::from_secs(0),
+                        finish_time: None,
+                        forfeited: false,
+                        waiting: waiting,
+                        splits: Vec::new(),
+                    }
+                })
+                .collect(),
+            bingo: bingo,
+            watchers: setup.watchers.clone(),
+        }
+    }
+
+    /// Records the finish time for the entrant and returns it together with
+    /// the entrant's placement. Returns `None` if the entrant didn't enter
+    /// the race or already finished.
+    pub fn finish(&mut self, id: u64) -> Option<(Duration, usize)> {
+        let elapsed = self.start.elapsed();
+        let place = self.entrants
+            .iter()
+            .filter(|e| e.finish_time.is_some())
+            .count() + 1;
+
+        let entrant = self.entrants
+            .iter_mut()
+            .find(|e| e.id == id && e.is_running());
+        if let Some(entrant) = entrant {
+            let time = elapsed - entrant.start;
+            entrant.finish_time = Some(time);
+            Some((time, place))
+        } else {
+            None
+        }
+    }
+
+    /// Enters the user into an async race, where they wait until they start
+    /// their run. Returns `false` if they already entered.
+    pub fn enter(&mut self, id: u64) -> bool {
+        if self.entrants.iter().any(|e| e.id == id) {
+            return false;
+        }
+        self.entrants
+            .push(Entrant {
+                      id: id,
+                      team: None,
+                      start: Duration::from_secs(0),
+                      finish_time: None,
+                      forfeited: false,
+                      waiting: true,
+                      splits: Vec::new(),
+                  });
+        true
+    }
+
+    /// Starts the waiting entrant's run. Returns `false` if they weren't
+    /// waiting.
+    pub fn start_run(&mut self, id: u64) -> bool {
+        let elapsed = self.start.elapsed();
+        match self.entrants.iter_mut().find(|e| e.id == id && e.waiting) {
+            Some(entrant) => {
+                entrant.waiting = false;
+                entrant.start = elapsed;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Lets the next runner of the relay team start once the entrant is
+    /// done. Returns the next runner, if there is one.
+    pub fn hand_off(&mut self, id: u64) -> Option<u64> {
+        let team = match self.entrants.iter().find(|e| e.id == id) {
+            Some(entrant) if self.team_mode == Some(TeamMode::Relay) => entrant.team.clone(),
+            _ => return None,
+        };
+        let elapsed = self.start.elapsed();
+        match self.entrants.iter_mut().find(|e| e.waiting && e.team == team) {
+            Some(next) => {
+                next.waiting = false;
+                next.start = elapsed;
+                Some(next.id)
+            }
+            None => None,
+        }
+    }
+
+    /// Records the time the entrant split at. Returns `false` if the entrant
+    /// isn't running in the race.
+    pub fn split(&mut self, id: u64) -> bool {
+        let time = self.start.elapsed();
+        match self.entrants.iter_mut().find(|e| e.id == id && e.is_running()) {
+            Some(entrant) => {
+                entrant.splits.push(time);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The entrant that got the furthest the quickest.
+    pub fn leader(&self) -> Option<&Entrant> {
+        self.entrants
+            .iter()
+            .filter(|e| !e.forfeited && !e.splits.is_empty())
+            .max_by(|a, b| {
+                        a.splits
+                            .len()
+                            .cmp(&b.splits.len())
+                            .then_with(|| b.splits.last().cmp(&a.splits.last()))
+                    })
+    }
+
+    /// How far the entrant is behind the leader at the entrant's latest split.
+    /// Negative if the entrant was quicker there, even though the leader got
+    /// further.
+    pub fn delta(&self, entrant: &Entrant) -> Option<(bool, Duration)> {
+        let index = match entrant.splits.len() {
+            0 => return None,
+            len => len - 1,
+        };
+        let leader = match self.leader().and_then(|leader| leader.splits.get(index)) {
+            Some(&time) => time,
+            None => return None,
+        };
+        let time = entrant.splits[index];
+        Some(if time >= leader {
+                 (false, time - leader)
+             } else {
+                 (true, leader - time)
+             })
+    }
+
+    /// Lets the entrant give up. A relay team can't finish without them, so
+    /// their teammates that didn't finish yet give up as well. Returns
+    /// `false` if the entrant isn't running in the race.
+    pub fn forfeit(&mut self, id: u64) -> bool {
+        let entrant = self.entrants
+            .iter()
+            .find(|e| e.id == id && e.finish_time.is_none() && !e.forfeited);
+        let team = match entrant {
+            Some(entrant) => entrant.team.clone(),
+            None => return false,
+        };
+        let relay = self.team_mode == Some(TeamMode::Relay);
+        for entrant in &mut self.entrants {
+            if entrant.id == id ||
+               (relay && entrant.team == team && entrant.finish_time.is_none()) {
+                entrant.forfeited = true;
+                entrant.waiting = false;
+            }
+        }
+        true
+    }
+
+    /// Whether the race is over. A lockout bingo race ends as soon as its
+    /// winner is determined.
+    pub fn is_finished(&self) -> bool {
+        let lockout = self.bingo.as_ref().map_or(false, |game| game.variant.lockout);
+        let finished = self.entrants.iter().any(|e| e.finish_time.is_some());
+        (lockout && finished) || self.entrants.iter().all(|e| !e.is_running() && !e.waiting)
+    }
+
+    /// The teams in the order their first runners entered.
+    pub fn teams(&self) -> Vec<&str> {
+        let mut teams = Vec::new();
+        for team in self.entrants.iter().filter_map(|e| e.team.as_ref()) {
+            if !teams.contains(&team.as_str()) {
+                teams.push(team.as_str());
+            }
+        }
+        teams
+    }
+
+    /// The team's time, once it is known. That's the best runner's time, or
+    /// the sum of all of them if everyone needs to finish.
+    pub fn team_time(&self, team: &str) -> Option<Duration> {
+        let members = self.entrants
+            .iter()
+            .filter(|e| e.team.as_ref().map_or(false, |t| t == team));
+        match self.team_mode {
+            Some(TeamMode::Best) => members.filter_map(|e| e.finish_time).min(),
+            Some(TeamMode::Sum) |
+            Some(TeamMode::Relay) => {
+                let mut total = Duration::from_secs(0);
+                for member in members {
+                    match member.finish_time {
+                        Some(time) => total += time,
+                        None => return None,
+                    }
+                }
+                Some(total)
+            }
+            None => None,
+        }
+    }
+
+    /// The entrants that finished the race, ordered by their finish time.
+    pub fn results(&self) -> Vec<(u64, Duration)> {
+        let mut results = self.entrants
+            .iter()
+            .filter_map(|e| e.finish_time.map(|t| (e.id, t)))
+            .collect::<Vec<_>>();
+        results.sort_by_key(|&(_, time)| time);
+        results
+    }
+}
+
+/// How far ahead a start time can lie, in seconds.
+const MAX_LEAD_TIME: u64 = 7 * 86_400;
+
+/// Parses when a scheduled race starts or an async race closes, either
+/// relative to `now` like `1h30m` or `45m`, or as the next time of day in UTC
+/// like `18:30`. Both `now` and the time are in seconds since the Unix epoch.
+/// The time lies within a week.
+pub fn parse_start_time(text: &str, now: u64) -> Option<u64> {
+    if let Some(index) = text.find(':') {
+        let hours: u64 = match text[..index].parse() {
+            Ok(hours) if hours < 24 => hours,
+            _ => return None,
+        };
+        let minutes: u64 = match text[index + 1..].parse() {
+            Ok(minutes) if minutes < 60 => minutes,
+            _ => return None,
+        };
+        let start = now - now % 86_400 + hours * 3600 + minutes * 60;
+        return Some(if start > now { start } else { start + 86_400 });
+    }
+
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        match c {
+            '0'...'9' => number.push(c),
+            'h' | 'm' => {
+                let value: u64 = match number.parse() {
+                    Ok(value) => value,
+                    Err(_) => return None,
+                };
+                let unit = if c == 'h' { 3600 } else { 60 };
+                seconds = match value.checked_mul(unit).and_then(|s| s.checked_add(seconds)) {
+                    Some(seconds) if seconds <= MAX_LEAD_TIME => seconds,
+                    _ => return None,
+                };
+                number.clear();
+            }
+            _ => return None,
+        }
+    }
+    if number.is_empty() && seconds > 0 {
+        now.checked_add(seconds)
+    } else {
+        None
+    }
+}
+
+pub fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    let centis = time.subsec_nanos() / 10_000_000;
+    format!("{}:{:02}:{:02}.{:02}",
+            secs / 3600,
+            (secs / 60) % 60,
+            secs % 60,
+            centis)
+}
+
+/// A race of three entrants, who are all ready.
+#[cfg(test)]
+fn setup() -> RaceSetup {
+    RaceSetup {
+        id: 1,
+        creator: 1,
+        entrants: vec![(1, true), (2, true), (3, true)],
+        team_mode: None,
+        teams: HashMap::new(),
+        game: None,
+        category: None,
+        goal: None,
+        bingo: None,
+        closed: false,
+        channel: 1,
+        guild: 1,
+        created: Instant::now(),
+        start_at: None,
+        reminded: false,
+        watchers: Vec::new(),
+    }
+}
+
+#[test]
+fn deltas_to_leader() {
+    let mut race = RaceInProgress::new(&setup(), String::new(), String::new(), None);
+    let seconds = Duration::from_secs;
+    race.entrants[0].splits = vec![seconds(60), seconds(130)];
+    race.entrants[1].splits = vec![seconds(55)];
+    race.entrants[2].splits = vec![seconds(60), seconds(120)];
+
+    assert_eq!(race.leader().map(|e| e.id), Some(3));
+    assert_eq!(race.delta(&race.entrants[0]), Some((false, seconds(10))));
+    assert_eq!(race.delta(&race.entrants[1]), Some((true, seconds(5))));
+
+    assert!(race.forfeit(3));
+    assert!(!race.forfeit(3));
+    assert_eq!(race.leader().map(|e| e.id), Some(1));
+}
+
+#[test]
+fn relay_teams() {
+    let mut teams = HashMap::new();
+    teams.insert(1, String::from("A"));
+    teams.insert(2, String::from("B"));
+    teams.insert(3, String::from("A"));
+    let setup = RaceSetup {
+        team_mode: Some(TeamMode::Relay),
+        teams: teams,
+        ..setup()
+    };
+    let mut race = RaceInProgress::new(&setup, String::new(), String::new(), None);
+    assert_eq!(race.teams(), ["A", "B"]);
+    assert!(!race.split(3));
+
+    assert!(race.finish(1).is_some());
+    assert_eq!(race.team_time("A"), None);
+    assert_eq!(race.hand_off(1), Some(3));
+    assert!(race.split(3));
+    assert!(race.finish(3).is_some());
+    assert_eq!(race.hand_off(3), None);
+    let total = race.entrants[0].finish_time.unwrap() + race.entrants[2].finish_time.unwrap();
+    assert_eq!(race.team_time("A"), Some(total));
+
+    assert!(!race.is_finished());
+    assert!(race.forfeit(2));
+    assert_eq!(race.team_time("B"), None);
+    assert!(race.is_finished());
+}
+
+#[test]
+fn parse_start_times() {
+    // 2017-06-10 14:45 UTC
+    let now = 1_497_105_900;
+    assert_eq!(parse_start_time("1h30m", now), Some(now + 90 * 60));
+    assert_eq!(parse_start_time("45m", now), Some(now + 45 * 60));
+    assert_eq!(parse_start_time("18:00", now), Some(now + 3 * 3600 + 15 * 60));
+    assert_eq!(parse_start_time("14:45", now), Some(now + 24 * 3600));
+    assert_eq!(parse_start_time("0m", now), None);
+    assert_eq!(parse_start_time("90", now), None);
+    assert_eq!(parse_start_time("25:00", now), None);
+    assert_eq!(parse_start_time("soon", now), None);
+    assert_eq!(parse_start_time("168h", now), Some(now + 7 * 86_400));
+    assert_eq!(parse_start_time("168h1m", now), None);
+    assert_eq!(parse_start_time("99999999999999999h", now), None);
+    assert_eq!(parse_start_time("1m", u64::max_value()), None);
+}
@@ -0,0 +1,197 @@
+use std::ascii::AsciiExt;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use history::{EntrantRecord, RaceRecord};
+
+const INITIAL_RATING: f64 = 1500.0;
+/// How many points a single race can move a rating at most.
+const K_FACTOR: f64 = 32.0;
+
+#[derive(Clone, Serialize)]
+pub struct Rating {
+    pub id: u64,
+    /// The name the runner had in their latest race.
+    pub name: String,
+    pub rating: f64,
+    pub races: u32,
+}
+
+/// The ratings of everyone that raced a category of a game.
+pub struct Leaderboard {
+    pub game: String,
+    pub category: String,
+    ratings: HashMap<u64, Rating>,
+}
+
+/// Elo ratings per game and category. Every race counts as a match against
+/// each other entrant, where the better placement wins and entrants that
+/// didn't finish lose against everyone that did. Teammates don't play against
+/// each other.
+#[derive(Default)]
+pub struct Ratings {
+    leaderboards: HashMap<(String, String), Leaderboard>,
+}
+
+impl Leaderboard {
+    pub fn title(&self) -> String {
+        format!("{} - {}", self.game, self.category)
+    }
+
+    /// The ratings, best first.
+    pub fn ranking(&self) -> Vec<&Rating> {
+        let mut ranking = self.ratings.values().collect::<Vec<_>>();
+        ranking.sort_by(|a, b| {
+                            b.rating
+                                .partial_cmp(&a.rating)
+                                .unwrap_or(Ordering::Equal)
+                                .then_with(|| a.name.cmp(&b.name))
+                        });
+        ranking
+    }
+}
+
+/// How the first entrant did against the second one.
+fn score(a: &EntrantRecord, b: &EntrantRecord) -> f64 {
+    match (a.place, b.place) {
+        (Some(a), Some(b)) if a < b => 1.0,
+        (Some(a), Some(b)) if a > b => 0.0,
+        (Some(_), None) => 1.0,
+        (None, Some(_)) => 0.0,
+        _ => 0.5,
+    }
+}
+
+fn expected_score(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+impl Ratings {
+    /// Rates the races in the order they were run.
+    pub fn new(races: &[RaceRecord]) -> Ratings {
+        let mut ratings = Ratings::default();
+        for race in races {
+            ratings.update(race);
+        }
+        ratings
+    }
+
+    /// Updates the ratings of the race's entrants. Races with a single
+    /// entrant don't change anything.
+    pub fn update(&mut self, race: &RaceRecord) {
+        if race.entrants.len() < 2 {
+            return;
+        }
+
+        let key = (race.game.to_lowercase(), race.category.to_lowercase());
+        let leaderboard = self.leaderboards
+            .entry(key)
+            .or_insert_with(|| {
+                                Leaderboard {
+                                    game: race.game.clone(),
+                                    category: race.category.clone(),
+                                    ratings: HashMap::new(),
+                                }
+                            });
+
+        let before = race.entrants
+            .iter()
+            .map(|e| leaderboard.ratings.get(&e.id).map_or(INITIAL_RATING, |r| r.rating))
+            .collect::<Vec<_>>();
+
+        for (index, entrant) in race.entrants.iter().enumerate() {
+            let opponents = race.entrants
+                .iter()
+                .enumerate()
+                .filter(|&(other, opponent)| {
+                            other != index &&
+                            (entrant.team.is_none() || opponent.team != entrant.team)
+                        })
+                .collect::<Vec<_>>();
+            if opponents.is_empty() {
+                continue;
+            }
+            let change = opponents
+                .iter()
+                .map(|&(other, opponent)| {
+                         score(entrant, opponent) - expected_score(before[index], before[other])
+                     })
+                .sum::<f64>();
+
+            let rating = leaderboard
+                .ratings
+                .entry(entrant.id)
+                .or_insert_with(|| {
+                                    Rating {
+                                        id: entrant.id,
+                                        name: String::new(),
+                                        rating: INITIAL_RATING,
+                                        races: 0,
+                                    }
+                                });
+            rating.name = entrant.name.clone();
+            rating.rating += K_FACTOR * change / opponents.len() as f64;
+            rating.races += 1;
+        }
+    }
+
+    /// The leaderboards, ordered by game and category.
+    pub fn leaderboards(&self) -> Vec<&Leaderboard> {
+        let mut leaderboards = self.leaderboards.values().collect::<Vec<_>>();
+        leaderboards.sort_by_key(|l| (l.game.to_lowercase(), l.category.to_lowercase()));
+        leaderboards
+    }
+
+    /// Finds the leaderboard by `<game> <category>` or `<game> | <category>`.
+    pub fn find(&self, query: &str) -> Option<&Leaderboard> {
+        let query = query
+            .split('|')
+            .map(|part| part.trim())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.leaderboards
+            .values()
+            .find(|l| format!("{} {}", l.game, l.category).eq_ignore_ascii_case(&query))
+    }
+}
+
+#[test]
+fn rate_races() {
+    let entrant = |id, place, forfeited| {
+        EntrantRecord {
+            id: id,
+            name: format!("Runner {}", id),
+            place: place,
+            time: None,
+            forfeited: forfeited,
+            team: None,
+            splits: Vec::new(),
+        }
+    };
+    let race = |entrants| {
+        RaceRecord {
+            id: 1,
+            game: String::from("Wind Waker"),
+            category: String::from("Any%"),
+            goal: None,
+            date: 0,
+            entrants: entrants,
+            teams: Vec::new(),
+        }
+    };
+
+    let ratings = Ratings::new(&[race(vec![entrant(2, Some(1), false),
+                                           entrant(1, Some(2), false),
+                                           entrant(3, None, true)]),
+                                 race(vec![entrant(4, Some(1), false)])]);
+    let leaderboard = ratings.find("wind waker | any%").unwrap();
+    assert_eq!(leaderboard.title(), "Wind Waker - Any%");
+    assert_eq!(leaderboard
+                   .ranking()
+                   .iter()
+                   .map(|r| (r.id, r.rating.round()))
+                   .collect::<Vec<_>>(),
+               [(2, 1516.0), (1, 1500.0), (3, 1484.0)]);
+    assert!(leaderboard.ratings.get(&4).is_none());
+    assert!(ratings.find("Wind Waker Any%").is_some());
+    assert!(ratings.find("Wind Waker").is_none());
+}
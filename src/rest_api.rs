@@ -1,32 +1,163 @@
 use rocket::request::State;
-use rocket::config::{Config, Environment};
+use rocket::config::{Config, ConfigError, Environment};
 use rocket_contrib::JSON;
 use std::sync::Arc;
-use {LSState, Layout, rocket};
-use std::thread::spawn;
+use {LSState, Layout, commands, rocket};
+use std::thread::{spawn, JoinHandle};
 use dotenv::var;
 use std::path::{Path, PathBuf};
 use rocket::response::NamedFile;
+use rocket::response::content::Content;
+use rocket::http::ContentType;
+use rocket::request::FromParam;
+use bingo::{self, BoardJson, RenderOptions};
+use history::RaceRecord;
+use rating::Rating;
 
-// #[get("/split")]
-// fn split(state: State<Arc<LSState>>) -> JSON<Layout> {
-//     let mut user = state.user(0, "REST User");
-//     user.timer.split();
-//     JSON(user.eval_layout())
-// }
-
-// #[get("/reset")]
-// fn reset(state: State<Arc<LSState>>) -> JSON<Layout> {
-//     let mut user = state.user(0, "REST User");
-//     user.timer.reset(true);
-//     JSON(user.eval_layout())
-// }
-
-// #[get("/state")]
-// fn get_state(state: State<Arc<LSState>>) -> JSON<Layout> {
-//     let mut user = state.user(0, "REST User");
-//     JSON(user.eval_layout())
-// }
+fn timer_user(state: &LSState, token: &str) -> Option<u64> {
+    state.tokens.read().get(token).cloned()
+}
+
+/// The timer routes are authenticated with the same token as the LiveSplit
+/// Server protocol, so they control the timer of the user it belongs to.
+#[get("/timer/<token>/split")]
+fn split(state: State<Arc<LSState>>, token: &str) -> Option<JSON<Layout>> {
+    let id = match timer_user(&state, token) {
+        Some(id) => id,
+        None => return None,
+    };
+    match state.users.get_mut(&id) {
+        Some(mut user) => user.timer.split(),
+        None => return None,
+    }
+    commands::announce_split(&state, id);
+    state.users.get_mut(&id).map(|mut user| JSON(user.eval_layout()))
+}
+
+#[get("/timer/<token>/reset")]
+fn reset(state: State<Arc<LSState>>, token: &str) -> Option<JSON<Layout>> {
+    timer_user(&state, token).and_then(|id| state.users.get_mut(&id)).map(|mut user| {
+        user.timer.reset(true);
+        JSON(user.eval_layout())
+    })
+}
+
+#[get("/timer/<token>/state")]
+fn get_state(state: State<Arc<LSState>>, token: &str) -> Option<JSON<Layout>> {
+    timer_user(&state, token)
+        .and_then(|id| state.users.get_mut(&id))
+        .map(|mut user| JSON(user.eval_layout()))
+}
+
+/// The finished races, most recent first.
+#[get("/races")]
+fn races(state: State<Arc<LSState>>) -> JSON<Vec<RaceRecord>> {
+    JSON(state.history.read().iter().rev().cloned().collect())
+}
+
+#[get("/races/<id>")]
+fn race(state: State<Arc<LSState>>, id: u64) -> Option<JSON<RaceRecord>> {
+    state
+        .history
+        .read()
+        .iter()
+        .find(|race| race.id == id)
+        .cloned()
+        .map(JSON)
+}
+
+/// The ratings of a game's category, best first.
+#[get("/leaderboard/<game>/<category>")]
+fn leaderboard(state: State<Arc<LSState>>,
+               game: String,
+               category: String)
+               -> Option<JSON<Vec<Rating>>> {
+    state
+        .ratings
+        .read()
+        .find(&format!("{} | {}", game, category))
+        .map(|leaderboard| JSON(leaderboard.ranking().into_iter().cloned().collect()))
+}
+
+pub struct PngFile<'a>(&'a str);
+pub struct JsonFile<'a>(&'a str);
+
+impl<'a> FromParam<'a> for PngFile<'a> {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        if param.ends_with(".png") {
+            Ok(PngFile(&param[..param.len() - 4]))
+        } else {
+            Err(param)
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for JsonFile<'a> {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        if param.ends_with(".json") {
+            Ok(JsonFile(&param[..param.len() - 5]))
+        } else {
+            Err(param)
+        }
+    }
+}
+
+fn render_board(state: &LSState,
+                template: &str,
+                seed: u32,
+                file: PngFile,
+                options: &RenderOptions)
+                -> Option<Content<Vec<u8>>> {
+    let (template, mode) = match (state.bingo_templates.get(template), bingo::mode(file.0)) {
+        (Some((_, template)), Some((mode, _))) => (template, mode),
+        _ => return None,
+    };
+    bingo::generate(template, seed, mode)
+        .and_then(|board| bingo::render_png(&board, &[], options))
+        .map(|image| Content(ContentType::PNG, image))
+        .ok()
+}
+
+#[get("/bingo/<template>/<seed>/<file>?<options>", rank = 1)]
+fn bingo_board_png_options(state: State<Arc<LSState>>,
+                           template: &str,
+                           seed: u32,
+                           file: PngFile,
+                           options: &str)
+                           -> Option<Content<Vec<u8>>> {
+    let mut render_options = RenderOptions::default();
+    render_options.apply_params(&options.split('&').collect::<Vec<_>>());
+    render_board(&state, template, seed, file, &render_options)
+}
+
+#[get("/bingo/<template>/<seed>/<file>", rank = 2)]
+fn bingo_board_png(state: State<Arc<LSState>>,
+                   template: &str,
+                   seed: u32,
+                   file: PngFile)
+                   -> Option<Content<Vec<u8>>> {
+    render_board(&state, template, seed, file, &RenderOptions::default())
+}
+
+#[get("/bingo/<template>/<seed>/<file>", rank = 3)]
+fn bingo_board_json(state: State<Arc<LSState>>,
+                    template: &str,
+                    seed: u32,
+                    file: JsonFile)
+                    -> Option<JSON<BoardJson>> {
+    let (name, template, mode, mode_name) = match (state.bingo_templates.get(template),
+                                                   bingo::mode(file.0)) {
+        (Some((name, template)), Some((mode, mode_name))) => (name, template, mode, mode_name),
+        _ => return None,
+    };
+    bingo::generate(template, seed, mode)
+        .map(|board| JSON(bingo::to_json(&board, name, seed, mode_name)))
+        .ok()
+}
 
 #[get("/botw/bingo/<board>/<file..>?<params>", rank = 3)]
 fn botw_bingo_params(board: &str, file: PathBuf, params: &str) -> Option<NamedFile> {
@@ -42,38 +173,58 @@ fn botw_bingo(board: &str, file: PathBuf) -> Option<NamedFile> {
 }
 
 #[get("/botw/bingo/<board>/tables/board.js", rank = 1)]
-fn botw_bingo_board(board: &str) -> String {
-    let board = match board {
-        "normal" => include_str!("../bingo-templates/botw.json"),
-        "korok" => include_str!("../bingo-templates/botw-korok.json"),
-        "shrine" => include_str!("../bingo-templates/botw-shrine.json"),
-        "plateau" => include_str!("../bingo-templates/botw-plateau.json"),
-        "compendium" => include_str!("../bingo-templates/botw-compendium.json"),
-        _ => return "Nope".to_string(),
+fn botw_bingo_board(state: State<Arc<LSState>>, board: &str) -> String {
+    let board = match state.bingo_templates.get(board) {
+        Some((_, template)) => template,
+        None => return "Nope".to_string(),
     };
     format!(r#"var bingoList = {}; $(function () {{ srl.bingo(bingoList, 5); }});"#,
             board)
 }
 
-pub fn start(state: Arc<LSState>) {
-    spawn(|| {
-        let mut config = Config::new(Environment::active().unwrap()).unwrap();
+fn port() -> Option<u16> {
+    var("PORT").ok().and_then(|p| p.parse().ok())
+}
 
-        config.set_address("0.0.0.0").unwrap();
+fn config() -> Result<Config, ConfigError> {
+    let mut config = Config::new(Environment::active()?)?;
 
-        if let Ok(Ok(port)) = var("PORT").map(|p| p.parse()) {
-            config.set_port(port);
-        }
+    config.set_address("0.0.0.0")?;
+
+    if let Some(port) = port() {
+        config.set_port(port);
+    }
 
-        rocket::custom(config, true)
+    Ok(config)
+}
+
+pub fn start(state: Arc<LSState>) -> JoinHandle<()> {
+    spawn(|| {
+        let config = match config() {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Invalid REST API configuration: {:?}", e);
+                return;
+            }
+        };
+
+        let error = rocket::custom(config, true)
             .mount("/",
-                   routes![//    split,
-                           //    reset,
-                           //    get_state,
+                   routes![split,
+                           reset,
+                           get_state,
+                           races,
+                           race,
+                           leaderboard,
                            botw_bingo,
                            botw_bingo_params,
-                           botw_bingo_board])
+                           botw_bingo_board,
+                           bingo_board_png,
+                           bingo_board_png_options,
+                           bingo_board_json])
             .manage(state)
             .launch();
-    });
+
+        error!("Couldn't launch the REST API: {:?}", error);
+    })
 }
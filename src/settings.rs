@@ -0,0 +1,256 @@
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::path::Path;
+use bingo::{RenderOptions, Templates};
+use error::Error;
+use storage;
+
+const FILE_NAME: &'static str = "guilds.json";
+/// Counting down blocks the frontend, so it can't take too long.
+const MAX_COUNTDOWN: u64 = 60;
+
+pub const KEYS: [&'static str; 6] = ["prefix",
+                                     "countdown",
+                                     "race-channels",
+                                     "bingo-templates",
+                                     "moderator-role",
+                                     "organizer-role"];
+
+/// The settings a guild's moderators chose. Settings that aren't set fall
+/// back to the bot's configuration.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+    pub prefix: Option<String>,
+    pub countdown: Option<u64>,
+    /// The channels races can be run in. Races can be run anywhere if empty.
+    pub race_channels: Vec<u64>,
+    /// The bingo templates that can be used. All of them can be used if
+    /// empty.
+    pub bingo_templates: Vec<String>,
+    /// Members with this role can change the settings, in addition to the
+    /// ones that can manage the guild.
+    pub moderator_role: Option<String>,
+    /// Members with this role can manage any race, in addition to the race's
+    /// creator and the moderators.
+    pub organizer_role: Option<String>,
+    /// How bingo boards are rendered, unless their template has a style of
+    /// its own.
+    pub bingo_style: Option<RenderOptions>,
+    /// The styles of individual bingo templates, keyed by their names.
+    pub template_bingo_styles: HashMap<String, RenderOptions>,
+}
+
+/// Parses a channel mention like `<#1234>` or a plain channel ID.
+fn parse_channel(text: &str, current: u64) -> Option<u64> {
+    if text == "here" {
+        return Some(current);
+    }
+    text.trim_left_matches("<#")
+        .trim_right_matches('>')
+        .parse()
+        .ok()
+}
+
+fn has_role(role: &Option<String>, roles: &[String]) -> bool {
+    role.as_ref()
+        .map_or(false, |role| roles.iter().any(|r| r.eq_ignore_ascii_case(role)))
+}
+
+/// Parses a role mention like `@Race Mods` or the role's name.
+fn parse_role(values: &[String]) -> Option<String> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(" ").trim_left_matches('@').to_owned())
+    }
+}
+
+impl GuildSettings {
+    pub fn allows_races_in(&self, channel: u64) -> bool {
+        self.race_channels.is_empty() || self.race_channels.contains(&channel)
+    }
+
+    pub fn allows_template(&self, template: &str) -> bool {
+        self.bingo_templates.is_empty() || self.bingo_templates.iter().any(|t| t == template)
+    }
+
+    pub fn is_moderator(&self, roles: &[String]) -> bool {
+        has_role(&self.moderator_role, roles)
+    }
+
+    pub fn is_organizer(&self, roles: &[String]) -> bool {
+        self.is_moderator(roles) || has_role(&self.organizer_role, roles)
+    }
+
+    /// The render options for the bingo template, falling back to the
+    /// guild's default style.
+    pub fn bingo_style(&self, template: Option<&str>) -> RenderOptions {
+        template
+            .and_then(|template| self.template_bingo_styles.get(template))
+            .or_else(|| self.bingo_style.as_ref())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Changes the style of the bingo template, or the guild's default style
+    /// without one. No style resets it.
+    pub fn set_bingo_style(&mut self, template: Option<&str>, style: Option<RenderOptions>) {
+        match (template, style) {
+            (Some(template), Some(style)) => {
+                self.template_bingo_styles.insert(template.to_owned(), style);
+            }
+            (Some(template), None) => {
+                self.template_bingo_styles.remove(template);
+            }
+            (None, style) => self.bingo_style = style,
+        }
+    }
+
+    /// Describes the setting's value.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let unset = || String::from("(default)");
+        Some(match key {
+                 "prefix" => self.prefix.clone().unwrap_or_else(unset),
+                 "countdown" => self.countdown.map_or_else(unset, |c| c.to_string()),
+                 "race-channels" => {
+                     if self.race_channels.is_empty() {
+                         String::from("(all)")
+                     } else {
+                         self.race_channels
+                             .iter()
+                             .map(|c| format!("<#{}>", c))
+                             .collect::<Vec<_>>()
+                             .join(" ")
+                     }
+                 }
+                 "bingo-templates" => {
+                     if self.bingo_templates.is_empty() {
+                         String::from("(all)")
+                     } else {
+                         self.bingo_templates.join(" ")
+                     }
+                 }
+                 "moderator-role" => self.moderator_role.clone().unwrap_or_else(unset),
+                 "organizer-role" => self.organizer_role.clone().unwrap_or_else(unset),
+                 _ => return None,
+             })
+    }
+
+    /// Changes the setting. No values reset it to the default.
+    pub fn set(&mut self,
+               key: &str,
+               values: &[String],
+               channel: u64,
+               templates: &Templates)
+               -> Result<(), Error> {
+        let invalid = || Error::InvalidSetting(key.to_owned());
+        match key {
+            "prefix" => self.prefix = values.get(0).cloned(),
+            "countdown" => {
+                self.countdown = match values.get(0).map(|v| v.parse()) {
+                    Some(Ok(countdown)) if countdown <= MAX_COUNTDOWN => Some(countdown),
+                    Some(_) => return Err(invalid()),
+                    None => None,
+                }
+            }
+            "race-channels" => {
+                self.race_channels = values
+                    .iter()
+                    .map(|v| parse_channel(v, channel).ok_or_else(&invalid))
+                    .collect::<Result<_, _>>()?
+            }
+            "bingo-templates" => {
+                // Aliases are stored by the name races refer to the template by.
+                self.bingo_templates = values
+                    .iter()
+                    .map(|v| {
+                             templates
+                                 .get(&v.to_lowercase())
+                                 .map(|(name, _)| name.to_owned())
+                                 .ok_or_else(&invalid)
+                         })
+                    .collect::<Result<_, _>>()?
+            }
+            "moderator-role" => self.moderator_role = parse_role(values),
+            "organizer-role" => self.organizer_role = parse_role(values),
+            _ => return Err(Error::UnknownSetting(key.to_owned())),
+        }
+        Ok(())
+    }
+}
+
+pub fn load<P: AsRef<Path>>(storage_path: P) -> HashMap<u64, GuildSettings> {
+    storage::load_json(storage_path, FILE_NAME)
+}
+
+pub fn save<P: AsRef<Path>>(storage_path: P,
+                            settings: &HashMap<u64, GuildSettings>)
+                            -> Result<(), Error> {
+    storage::save_json(storage_path, FILE_NAME, settings)
+}
+
+#[test]
+fn change_settings() {
+    let mut settings = GuildSettings::default();
+    let templates = Templates::default();
+    assert_eq!(settings.get("race-channels"), Some(String::from("(all)")));
+    assert!(settings.allows_races_in(3));
+
+    settings
+        .set("race-channels",
+             &[String::from("<#12>"), String::from("here")],
+             34,
+             &templates)
+        .unwrap();
+    assert_eq!(settings.get("race-channels"), Some(String::from("<#12> <#34>")));
+    assert!(settings.allows_races_in(34));
+    assert!(!settings.allows_races_in(3));
+
+    assert_eq!(settings.set("countdown", &[String::from("soon")], 1, &templates),
+               Err(Error::InvalidSetting(String::from("countdown"))));
+    settings.set("countdown", &[String::from("5")], 1, &templates).unwrap();
+    assert_eq!(settings.countdown, Some(5));
+    assert_eq!(settings.set("countdown", &[String::from("86400")], 1, &templates),
+               Err(Error::InvalidSetting(String::from("countdown"))));
+    settings.set("countdown", &[], 1, &templates).unwrap();
+    assert_eq!(settings.countdown, None);
+
+    settings
+        .set("bingo-templates",
+             &[String::from("GP"), String::from("normal")],
+             1,
+             &templates)
+        .unwrap();
+    assert_eq!(settings.get("bingo-templates"), Some(String::from("plateau normal")));
+    assert!(settings.allows_template("plateau"));
+    assert!(!settings.allows_template("korok"));
+    assert_eq!(settings.set("bingo-templates", &[String::from("nromal")], 1, &templates),
+               Err(Error::InvalidSetting(String::from("bingo-templates"))));
+
+    settings
+        .set("moderator-role",
+             &[String::from("@Race"), String::from("Mods")],
+             1,
+             &templates)
+        .unwrap();
+    assert!(settings.is_moderator(&[String::from("race mods")]));
+    assert!(!settings.is_moderator(&[String::from("Runners")]));
+    assert!(settings.is_organizer(&[String::from("race mods")]));
+
+    settings.set("organizer-role", &[String::from("Runners")], 1, &templates).unwrap();
+    assert!(settings.is_organizer(&[String::from("Runners")]));
+    assert!(!settings.is_moderator(&[String::from("Runners")]));
+
+    assert_eq!(settings.set("volume", &[], 1, &templates),
+               Err(Error::UnknownSetting(String::from("volume"))));
+
+    let mut style = RenderOptions::default();
+    style.apply("size=150");
+    settings.set_bingo_style(None, Some(style.clone()));
+    assert!(settings.bingo_style(Some("korok")) == style);
+    settings.set_bingo_style(Some("korok"), Some(RenderOptions::default()));
+    assert!(settings.bingo_style(Some("korok")) == RenderOptions::default());
+    settings.set_bingo_style(Some("korok"), None);
+    assert!(settings.bingo_style(Some("korok")) == style);
+}
@@ -0,0 +1,35 @@
+use std::fs::{self, File};
+use std::path::Path;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use error::Error;
+
+/// Loads the JSON file from the storage directory. A missing file is empty,
+/// while a broken one gets logged and replaced on the next save.
+pub fn load_json<T, P>(storage_path: P, file_name: &str) -> T
+    where T: DeserializeOwned + Default,
+          P: AsRef<Path>
+{
+    let path = storage_path.as_ref().join(file_name);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(_) => return T::default(),
+    };
+    serde_json::from_reader(file).unwrap_or_else(|e| {
+        error!("Couldn't load {}: {}", path.display(), e);
+        T::default()
+    })
+}
+
+pub fn save_json<T, P>(storage_path: P, file_name: &str, value: &T) -> Result<(), Error>
+    where T: Serialize + ?Sized,
+          P: AsRef<Path>
+{
+    let path = storage_path.as_ref().join(file_name);
+    fs::create_dir_all(storage_path.as_ref())
+        .and_then(|_| File::create(&path))
+        .map_err(|e| e.to_string())
+        .and_then(|file| serde_json::to_writer(file, value).map_err(|e| e.to_string()))
+        .map_err(|e| Error::Storage(format!("Couldn't save {}: {}", path.display(), e)))
+}
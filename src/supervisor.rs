@@ -0,0 +1,23 @@
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MIN_RESTART_DELAY: u64 = 1;
+const MAX_RESTART_DELAY: u64 = 5 * 60;
+
+/// Runs the client again whenever it stops. The delay before restarting
+/// doubles with every restart, unless the client kept running for a while.
+pub fn supervise<F: FnMut()>(name: &str, mut run: F) -> ! {
+    let mut delay = MIN_RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        run();
+
+        if started.elapsed() > Duration::from_secs(MAX_RESTART_DELAY) {
+            delay = MIN_RESTART_DELAY;
+        }
+        info!("Restarting the {} in {} seconds", name, delay);
+        thread::sleep(Duration::from_secs(delay));
+        delay = cmp::min(delay * 2, MAX_RESTART_DELAY);
+    }
+}
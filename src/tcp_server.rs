@@ -0,0 +1,222 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::{spawn, JoinHandle};
+use dotenv::var;
+use livesplit_core::{TimeSpan, Timer, TimerPhase, TimingMethod};
+use {LSState, commands};
+
+const DEFAULT_ADDRESS: &'static str = "0.0.0.0:16834";
+
+fn address() -> String {
+    var("LIVESPLIT_SERVER_ADDRESS").unwrap_or_else(|_| String::from(DEFAULT_ADDRESS))
+}
+
+fn parse_time(text: &str) -> Option<TimeSpan> {
+    let mut seconds = 0.0;
+    for part in text.trim().split(':') {
+        let value: f64 = match part.parse() {
+            Ok(value) => value,
+            Err(_) => return None,
+        };
+        seconds = seconds * 60.0 + value;
+    }
+    Some(TimeSpan::from_seconds(seconds))
+}
+
+fn format_time(time: Option<TimeSpan>) -> String {
+    let total = match time {
+        Some(time) => time.total_seconds(),
+        None => return String::from("-"),
+    };
+    let (sign, total) = if total < 0.0 { ("-", -total) } else { ("", total) };
+    let centis = (total * 100.0) as u64;
+    format!("{}{}:{:02}:{:02}.{:02}",
+            sign,
+            centis / 360_000,
+            (centis / 6_000) % 60,
+            (centis / 100) % 60,
+            centis % 100)
+}
+
+/// Executes a single protocol command for the user. Returns the response
+/// line for the commands that query the timer.
+pub fn execute(state: &LSState, user: u64, line: &str) -> Option<String> {
+    let (response, split) = match state.users.get_mut(&user) {
+        Some(mut user) => control(&mut user.timer, line),
+        None => return Some(String::from("User not found")),
+    };
+    // The user is unlocked by now, as the race looks up every entrant.
+    if split {
+        commands::announce_split(state, user);
+    }
+    response
+}
+
+/// Executes the command on the timer. Returns the response line, if there is
+/// one, and whether the timer split.
+fn control(timer: &mut Timer, line: &str) -> (Option<String>, bool) {
+    let mut split = false;
+    let mut splits = line.trim().splitn(2, ' ');
+    let command = splits.next().unwrap_or("");
+    let argument = splits.next().unwrap_or("");
+
+    match command {
+        "starttimer" => timer.start(),
+        "startorsplit" => {
+            if timer.current_phase() == TimerPhase::NotRunning {
+                timer.start();
+            } else {
+                timer.split();
+                split = true;
+            }
+        }
+        "split" => {
+            timer.split();
+            split = true;
+        }
+        "unsplit" => timer.undo_split(),
+        "skipsplit" => timer.skip_split(),
+        "pause" => timer.pause(),
+        "resume" => timer.resume(),
+        "reset" => timer.reset(true),
+        "initgametime" => timer.initialize_game_time(),
+        "setgametime" => {
+            if let Some(time) = parse_time(argument) {
+                timer.set_game_time(time);
+            }
+        }
+        "setloadingtimes" => {
+            if let Some(time) = parse_time(argument) {
+                timer.set_loading_times(time);
+            }
+        }
+        "pausegametime" => timer.pause_game_time(),
+        "unpausegametime" => timer.unpause_game_time(),
+        "setcomparison" => timer.set_current_comparison(argument),
+        "switchto" => {
+            match argument {
+                "realtime" => timer.set_current_timing_method(TimingMethod::RealTime),
+                "gametime" => timer.set_current_timing_method(TimingMethod::GameTime),
+                _ => {}
+            }
+        }
+        "getcurrenttime" => {
+            let time = timer.current_time();
+            let time = if timer.current_timing_method() == TimingMethod::GameTime {
+                time.game_time
+            } else {
+                time.real_time
+            };
+            return (Some(format_time(time)), false);
+        }
+        "getsplitindex" => return (Some(timer.current_split_index().to_string()), false),
+        "getcurrentsplitname" => {
+            let name = timer
+                .current_split()
+                .map_or(String::from("-"), |s| s.name().to_owned());
+            return (Some(name), false);
+        }
+        "getcurrenttimerphase" => {
+            let phase = match timer.current_phase() {
+                TimerPhase::NotRunning => "NotRunning",
+                TimerPhase::Running => "Running",
+                TimerPhase::Ended => "Ended",
+                TimerPhase::Paused => "Paused",
+            };
+            return (Some(String::from(phase)), false);
+        }
+        _ => {}
+    }
+
+    (None, split)
+}
+
+fn connection(state: &LSState, stream: TcpStream) {
+    let mut output = match stream.try_clone() {
+        Ok(output) => output,
+        Err(_) => return,
+    };
+    let mut user = None;
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        let response = if line.starts_with("auth ") {
+            let token = line[5..].trim();
+            user = state.tokens.read().get(token).cloned();
+            Some(if user.is_some() {
+                     "Authenticated"
+                 } else {
+                     "Invalid token"
+                 }
+                 .to_owned())
+        } else if let Some(user) = user {
+            execute(state, user, &line)
+        } else {
+            Some(String::from("Not authenticated"))
+        };
+
+        if let Some(response) = response {
+            if writeln!(output, "{}", response).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Listens for connections speaking the text protocol of the LiveSplit Server
+/// component. Every connection first needs to authenticate with `auth
+/// <token>`, using the token the user got from the `!server-token` command.
+pub fn start(state: Arc<LSState>) -> JoinHandle<()> {
+    spawn(move || {
+        let address = address();
+        let listener = match TcpListener::bind(address.as_str()) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Couldn't start the LiveSplit Server on {}: {}", address, e);
+                return;
+            }
+        };
+        info!("LiveSplit Server listening on {}", address);
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let state = state.clone();
+                    spawn(move || connection(&state, stream));
+                }
+                Err(e) => warn!("Couldn't accept a LiveSplit Server connection: {}", e),
+            }
+        }
+    })
+}
+
+#[test]
+fn parse_times() {
+    assert_eq!(parse_time("1:02:03.5").map(|t| t.total_seconds()), Some(3723.5));
+    assert_eq!(parse_time("42").map(|t| t.total_seconds()), Some(42.0));
+    assert!(parse_time("1:x").is_none());
+    assert_eq!(format_time(parse_time("1:02:03.5")), "1:02:03.50");
+}
+
+#[test]
+fn drive_timer() {
+    let state = LSState::new(::config::Config {
+                                 storage_path: String::from("target/test-storage/drive-timer"),
+                                 ..::config::Config::default()
+                             });
+    state.user(1, "Runner");
+
+    assert_eq!(execute(&state, 1, "getcurrenttimerphase"),
+               Some(String::from("NotRunning")));
+    assert_eq!(execute(&state, 1, "startorsplit"), None);
+    assert_eq!(execute(&state, 1, "getcurrenttimerphase"),
+               Some(String::from("Running")));
+    assert_eq!(execute(&state, 1, "getsplitindex"), Some(String::from("0")));
+    assert_eq!(execute(&state, 1, "getcurrentsplitname"),
+               Some(String::from("First")));
+}